@@ -1,24 +1,98 @@
 #![feature(test)]
 
 extern crate aitios_geom;
+extern crate aitios_sim;
 extern crate fixtures;
+extern crate rayon;
 extern crate test;
 
+use aitios_geom::prelude::*;
 use aitios_geom::{TangentSpace, Vec3};
-use fixtures::venus::make_tracer;
+use aitios_sim::{Acceleration, FlowQuery, Tracer};
+use fixtures::venus::{make_tracer, make_tracer_with_acceleration};
+use rayon::prelude::*;
+
+/// Rays per side of the grid of rays shot down onto the scene in batch benchmarks.
+const GRID_SIZE: usize = 64;
 
 #[bench]
 fn trace_straight(b: &mut test::Bencher) {
-    let tracer = make_tracer();
+    bench_straight(b, make_tracer())
+}
+
+#[bench]
+fn trace_straight_bvh(b: &mut test::Bencher) {
+    bench_straight(b, make_tracer_with_acceleration(Acceleration::Bvh))
+}
+
+#[bench]
+fn trace_parabolic(b: &mut test::Bencher) {
+    bench_parabolic(b, make_tracer())
+}
+
+#[bench]
+fn trace_parabolic_bvh(b: &mut test::Bencher) {
+    bench_parabolic(b, make_tracer_with_acceleration(Acceleration::Bvh))
+}
+
+#[bench]
+fn trace_flow(b: &mut test::Bencher) {
+    bench_flow(b, make_tracer())
+}
+
+#[bench]
+fn trace_flow_bvh(b: &mut test::Bencher) {
+    bench_flow(b, make_tracer_with_acceleration(Acceleration::Bvh))
+}
+
+#[bench]
+fn trace_straight_grid_single(b: &mut test::Bencher) {
+    bench_straight_grid_single(b, make_tracer())
+}
+
+#[bench]
+fn trace_straight_grid_batch(b: &mut test::Bencher) {
+    bench_straight_grid_batch(b, make_tracer())
+}
+
+#[bench]
+fn trace_straight_grid_single_bvh(b: &mut test::Bencher) {
+    bench_straight_grid_single(b, make_tracer_with_acceleration(Acceleration::Bvh))
+}
+
+#[bench]
+fn trace_straight_grid_batch_bvh(b: &mut test::Bencher) {
+    bench_straight_grid_batch(b, make_tracer_with_acceleration(Acceleration::Bvh))
+}
+
+#[bench]
+fn trace_flow_grid_single(b: &mut test::Bencher) {
+    bench_flow_grid_single(b, make_tracer())
+}
+
+#[bench]
+fn trace_flow_grid_batch(b: &mut test::Bencher) {
+    bench_flow_grid_batch(b, make_tracer())
+}
+
+#[bench]
+fn trace_flow_grid_single_bvh(b: &mut test::Bencher) {
+    bench_flow_grid_single(b, make_tracer_with_acceleration(Acceleration::Bvh))
+}
+
+#[bench]
+fn trace_flow_grid_batch_bvh(b: &mut test::Bencher) {
+    bench_flow_grid_batch(b, make_tracer_with_acceleration(Acceleration::Bvh))
+}
+
+fn bench_straight(b: &mut test::Bencher, tracer: Tracer) {
     let top = Vec3::new(0.0, 100.0, 0.0);
     let down = Vec3::new(0.0, -1.0, 0.0);
 
     b.iter(|| tracer.trace_straight(top, down).unwrap())
 }
 
-#[bench]
-fn trace_parabolic(b: &mut test::Bencher) {
-    let tracer = make_tracer();
+fn bench_parabolic(b: &mut test::Bencher, tracer: Tracer) {
     let top = Vec3::new(0.0, 100.0, 0.0);
     let up = Vec3::new(0.0, 1.0, 0.0);
     let down = -up;
@@ -29,16 +103,80 @@ fn trace_parabolic(b: &mut test::Bencher) {
     b.iter(|| tracer.trace_parabolic(ontop, up, parabola_height).unwrap())
 }
 
-#[bench]
-fn trace_flow(b: &mut test::Bencher) {
-    let tracer = make_tracer();
+fn bench_flow(b: &mut test::Bencher, tracer: Tracer) {
     let top = Vec3::new(0.0, 100.0, 0.0);
     let up = Vec3::new(0.0, 1.0, 0.0);
     let down = -up;
     let hit = tracer.trace_straight(top, down).unwrap();
-    let normal = hit.triangle.normal();
-    let tangent = hit.triangle.tangent();
+    let normal = hit.normal;
+    let tangent = tracer.triangle(hit.triangle_idx).unwrap().tangent();
     let ontop = hit.intersection_point + 0.000001 * normal;
 
     b.iter(|| tracer.trace_flow(ontop, normal, tangent, 0.05).unwrap());
 }
+
+/// Traces each ray of the grid on its own, in parallel like the batch.
+fn bench_straight_grid_single(b: &mut test::Bencher, tracer: Tracer) {
+    let rays = grid_rays(&tracer);
+
+    b.iter(|| {
+        rays.par_iter()
+            .map(|&(from, direction)| tracer.trace_straight(from, direction))
+            .collect::<Vec<_>>()
+    })
+}
+
+fn bench_straight_grid_batch(b: &mut test::Bencher, tracer: Tracer) {
+    let rays = grid_rays(&tracer);
+
+    b.iter(|| tracer.trace_straight_batch(&rays))
+}
+
+/// Traces each flow query of the grid on its own, in parallel like the batch.
+fn bench_flow_grid_single(b: &mut test::Bencher, tracer: Tracer) {
+    let queries = grid_flow_queries(&tracer);
+
+    b.iter(|| {
+        queries
+            .par_iter()
+            .map(|q| tracer.trace_flow(q.from, q.up, q.tangential_direction, q.flow_distance))
+            .collect::<Vec<_>>()
+    })
+}
+
+fn bench_flow_grid_batch(b: &mut test::Bencher, tracer: Tracer) {
+    let queries = grid_flow_queries(&tracer);
+
+    b.iter(|| tracer.trace_flow_batch(&queries))
+}
+
+/// Rays shot straight down onto the scene from a regular grid above its bounds, in
+/// row-major order.
+fn grid_rays(tracer: &Tracer) -> Vec<(Vec3, Vec3)> {
+    let bounds = tracer.bounds();
+    let extent = bounds.max - bounds.min;
+    let down = Vec3::new(0.0, -1.0, 0.0);
+
+    (0..GRID_SIZE)
+        .flat_map(|row| (0..GRID_SIZE).map(move |column| (row, column)))
+        .map(|(row, column)| {
+            let x = bounds.min.x + extent.x * (column as f32 + 0.5) / GRID_SIZE as f32;
+            let z = bounds.min.z + extent.z * (row as f32 + 0.5) / GRID_SIZE as f32;
+            (Vec3::new(x, bounds.max.y + 1.0, z), down)
+        })
+        .collect()
+}
+
+/// Flowing tons starting where the grid rays hit the scene.
+fn grid_flow_queries(tracer: &Tracer) -> Vec<FlowQuery> {
+    grid_rays(tracer)
+        .into_iter()
+        .filter_map(|(from, direction)| tracer.trace_straight(from, direction))
+        .map(|hit| FlowQuery {
+            from: hit.intersection_point,
+            up: hit.normal,
+            tangential_direction: tracer.triangle(hit.triangle_idx).unwrap().tangent(),
+            flow_distance: 0.05,
+        })
+        .collect()
+}
@@ -1,5 +1,5 @@
 use asset::obj;
-use sim::{Simulation, TonSourceBuilder, SurfelRule, SurfelData, Tracer};
+use sim::{Acceleration, Simulation, TonSourceBuilder, SurfelRule, SurfelData, Tracer, Tracing};
 use surf::{SurfaceBuilder, SurfelSampling};
 use scene::{Entity, Mesh};
 
@@ -14,6 +14,17 @@ pub fn make_tracer() -> Tracer {
     Tracer::new(tris)
 }
 
+/// Creates a tracer for the venus scene that uses the given acceleration structure.
+pub fn make_tracer_with_acceleration(acceleration: Acceleration) -> Tracer {
+    let ents = entitites();
+    let tris = ents.iter().flat_map(|e| e.mesh.triangles());
+    let config = Tracing {
+        acceleration,
+        ..Default::default()
+    };
+    Tracer::new_with_config(tris, &config)
+}
+
 /// Creates a moderately complex simulation with a scene  from a ~1.7MB OBJ (30832 triangles)
 /// of a simplified version of the venus the milo standing on a flattened out icosphere.
 ///
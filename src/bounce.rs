@@ -0,0 +1,130 @@
+use direction;
+use geom::prelude::*;
+use geom::Vec3;
+use rand::{self, Rng};
+use sampling::{Uniform, UnitHemisphere};
+use std::f32::consts::PI;
+
+/// Distribution of outgoing directions when tons bounce off a surface in a straight
+/// line or on a parabolic trajectory.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BounceModel {
+    /// Regards the surface as completely diffuse, uniformly sampling the hemisphere
+    /// around the normal.
+    Uniform,
+    /// Cosine-weighted hemisphere around the normal, favoring directions close to
+    /// the normal.
+    Cosine,
+    /// Perfect mirror reflection of the incoming direction.
+    Mirror,
+    /// Lobe around the mirror direction that widens with roughness, from a perfect
+    /// mirror at `0.0` to a broad lobe at `1.0`.
+    Glossy { roughness: f32 },
+}
+
+impl Default for BounceModel {
+    fn default() -> Self {
+        BounceModel::Uniform
+    }
+}
+
+impl BounceModel {
+    /// Samples an outgoing direction for a ton arriving from the given incoming direction
+    /// on a surface with the given normalized normal.
+    pub fn sample(&self, normal: Vec3, incoming_direction: Vec3) -> Vec3 {
+        let mut rng = rand::thread_rng();
+        let incoming_direction = incoming_direction.normalize();
+
+        match *self {
+            BounceModel::Uniform => direction::to_world(normal, UnitHemisphere::PosZ.uniform()),
+            BounceModel::Cosine => direction::cosine_weighted(normal, rng.gen(), rng.gen()),
+            BounceModel::Mirror => direction::reflect(incoming_direction, normal),
+            BounceModel::Glossy { roughness } => {
+                // Normal on the side the ton came from
+                let normal = if normal.dot(incoming_direction) > 0.0 {
+                    -normal
+                } else {
+                    normal
+                };
+
+                let mirror = direction::reflect(incoming_direction, normal);
+                let lobe = direction::to_world(mirror, phong_lobe(roughness, rng.gen(), rng.gen()));
+
+                // Parts of the lobe below the surface are mirrored back up
+                if lobe.dot(normal) < 0.0 {
+                    direction::reflect(lobe, normal)
+                } else {
+                    lobe
+                }
+            }
+        }
+    }
+}
+
+/// Maps two uniform numbers to a direction around positive z, distributed with a phong lobe
+/// whose exponent is derived from the given roughness.
+fn phong_lobe(roughness: f32, u: f32, v: f32) -> Vec3 {
+    let roughness = roughness.max(0.001).min(1.0);
+    let exponent = 2.0 / (roughness * roughness) - 2.0;
+
+    let cos_theta = u.powf((exponent + 1.0).recip());
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+    Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_mirror_reflects_exactly() {
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let reflected = BounceModel::Mirror.sample(up, Vec3::new(1.0, -1.0, 0.0));
+        let expected = Vec3::new(1.0, 1.0, 0.0).normalize();
+        assert_relative_eq!(reflected, expected, epsilon = 0.0001);
+
+        let tilted = Vec3::new(0.0, 1.0, 1.0).normalize();
+        let reflected = BounceModel::Mirror.sample(tilted, Vec3::new(0.0, -1.0, 0.0));
+        assert_relative_eq!(reflected, Vec3::new(0.0, 0.0, 1.0), epsilon = 0.0001);
+    }
+
+    #[test]
+    fn test_bounces_stay_in_hemisphere() {
+        let models = [
+            BounceModel::Uniform,
+            BounceModel::Cosine,
+            BounceModel::Mirror,
+            BounceModel::Glossy { roughness: 0.0 },
+            BounceModel::Glossy { roughness: 0.5 },
+            BounceModel::Glossy { roughness: 1.0 },
+        ];
+        let normals = [
+            Vec3::new(0.0, 1.0, 0.0),
+            Vec3::new(0.0, 0.0, -1.0),
+            Vec3::new(1.0, 1.0, 1.0).normalize(),
+        ];
+
+        for model in models.iter() {
+            for &normal in normals.iter() {
+                // Head-on, oblique and grazing incoming directions from the normal side
+                let tangent = direction::orthonormal_basis(normal).0;
+                let incoming_directions = [-normal, tangent - normal, tangent - 0.01 * normal];
+
+                for &incoming in incoming_directions.iter() {
+                    for _ in 0..1000 {
+                        let outgoing = model.sample(normal, incoming);
+                        assert_relative_eq!(outgoing.magnitude(), 1.0, epsilon = 0.0001);
+                        assert!(
+                            outgoing.dot(normal) >= -0.0001,
+                            "{:?} bounced {:?} below the surface with normal {:?}",
+                            model,
+                            outgoing,
+                            normal
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
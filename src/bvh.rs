@@ -0,0 +1,522 @@
+use geom::prelude::*;
+use geom::{Aabb, TupleTriangle, Vec3, Vertex};
+use std::f32::{EPSILON, INFINITY, NEG_INFINITY};
+use std::u32;
+
+/// Number of buckets that triangle centroids are sorted into along an axis when
+/// evaluating split candidates.
+const SAH_BUCKET_COUNT: usize = 12;
+/// Nodes with this many triangles or less are never split.
+const MAX_LEAF_TRIANGLES: usize = 4;
+/// Cost of traversing an interior node relative to intersecting a triangle.
+const TRAVERSAL_COST: f32 = 0.125;
+
+/// Bounding volume hierarchy over triangles, built with the surface area heuristic.
+///
+/// Nodes are stored flattened in depth-first order, so the first child of an interior
+/// node directly follows it and only the index of the second child is stored.
+pub struct Bvh {
+    nodes: Vec<Node>,
+    /// Triangles, reordered so the triangles of each leaf are contiguous
+    triangles: Vec<LeafTriangle>,
+}
+
+/// Vertex positions of a triangle in a leaf, with the index of the triangle in the
+/// triangles the hierarchy was built from.
+struct LeafTriangle {
+    positions: (Vec3, Vec3, Vec3),
+    idx: usize,
+}
+
+struct Node {
+    min: Vec3,
+    max: Vec3,
+    /// Index of the first triangle for leaves, index of the second child for
+    /// interior nodes
+    offset: u32,
+    /// Number of triangles for leaves, zero for interior nodes
+    count: u32,
+    /// Axis that interior nodes are split along
+    axis: u8,
+}
+
+/// Triangle bounds and centroid used during construction.
+struct BuildTriangle {
+    idx: usize,
+    min: Vec3,
+    max: Vec3,
+    centroid: Vec3,
+}
+
+#[derive(Clone, Copy)]
+struct Bucket {
+    count: usize,
+    min: Vec3,
+    max: Vec3,
+}
+
+impl Bvh {
+    /// Builds a hierarchy over the given triangles, which are referred to by their index
+    /// in the slice.
+    ///
+    /// Panics if there are more triangles than fit into the 32 bit indexes of the nodes.
+    pub fn new(triangles: &[TupleTriangle<Vertex>]) -> Self {
+        assert!(
+            triangles.len() <= u32::MAX as usize / 2,
+            "Too many triangles for a bounding volume hierarchy"
+        );
+
+        let mut build_triangles: Vec<BuildTriangle> = triangles
+            .iter()
+            .enumerate()
+            .map(|(idx, triangle)| {
+                let (a, b, c) = triangle.positions();
+                let min = component_min(component_min(a, b), c);
+                let max = component_max(component_max(a, b), c);
+                BuildTriangle {
+                    idx,
+                    min,
+                    max,
+                    centroid: (a + b + c) / 3.0,
+                }
+            })
+            .collect();
+
+        let mut nodes = Vec::with_capacity(2 * triangles.len() / MAX_LEAF_TRIANGLES + 1);
+        if build_triangles.is_empty() {
+            nodes.push(Node {
+                min: Vec3::new(0.0, 0.0, 0.0),
+                max: Vec3::new(0.0, 0.0, 0.0),
+                offset: 0,
+                count: 0,
+                axis: 0,
+            });
+        } else {
+            build_recursive(&mut nodes, &mut build_triangles, 0);
+        }
+
+        let triangles = build_triangles
+            .iter()
+            .map(|t| LeafTriangle {
+                positions: triangles[t.idx].positions(),
+                idx: t.idx,
+            })
+            .collect();
+
+        Bvh { nodes, triangles }
+    }
+
+    /// Gets the bounds of all triangles in the hierarchy.
+    pub fn bounds(&self) -> Aabb {
+        let root = &self.nodes[0];
+        Aabb {
+            min: root.min,
+            max: root.max,
+        }
+    }
+
+    /// Finds the index of the closest triangle hit by the ray and the parameter of the
+    /// hit, so that `origin + t * direction` is the intersection point.
+    pub fn ray_intersection(&self, origin: Vec3, direction: Vec3) -> Option<(usize, f32)> {
+        self.closest_hit(origin, direction, INFINITY)
+    }
+
+    /// Finds the index of the closest triangle hit by the line segment from the origin with
+    /// the given length along the normalized direction, and the distance of the hit from
+    /// the origin.
+    pub fn line_segment_intersection(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        length: f32,
+    ) -> Option<(usize, f32)> {
+        self.closest_hit(origin, direction, length)
+    }
+
+    /// Like `ray_intersection` for each of the given rays, given as origin and direction,
+    /// but traversing the hierarchy once for all of them, see `closest_hits`.
+    pub fn ray_intersections(&self, rays: &[(Vec3, Vec3)]) -> Vec<Option<(usize, f32)>> {
+        let queries: Vec<(Vec3, Vec3, f32)> = rays
+            .iter()
+            .map(|&(origin, direction)| (origin, direction, INFINITY))
+            .collect();
+        self.closest_hits(&queries)
+    }
+
+    /// Like `line_segment_intersection` for each of the given segments, given as origin,
+    /// normalized direction and length, but traversing the hierarchy once for all of
+    /// them, see `closest_hits`.
+    pub fn line_segment_intersections(
+        &self,
+        segments: &[(Vec3, Vec3, f32)],
+    ) -> Vec<Option<(usize, f32)>> {
+        self.closest_hits(segments)
+    }
+
+    fn closest_hit(&self, origin: Vec3, direction: Vec3, max_t: f32) -> Option<(usize, f32)> {
+        if self.triangles.is_empty() {
+            return None;
+        }
+
+        let inv_direction = reciprocal(direction);
+        let negative = [
+            inv_direction.x < 0.0,
+            inv_direction.y < 0.0,
+            inv_direction.z < 0.0,
+        ];
+
+        let mut closest: Option<(usize, f32)> = None;
+        let mut closest_t = max_t;
+
+        let mut stack = Vec::with_capacity(64);
+        stack.push(0);
+
+        while let Some(node_idx) = stack.pop() {
+            let node = &self.nodes[node_idx];
+
+            if !intersects_box(node, origin, inv_direction, closest_t) {
+                continue;
+            }
+
+            if node.count > 0 {
+                let first = node.offset as usize;
+                for triangle in &self.triangles[first..(first + node.count as usize)] {
+                    if let Some(t) = intersect_triangle(triangle.positions, origin, direction) {
+                        if t <= closest_t {
+                            closest_t = t;
+                            closest = Some((triangle.idx, t));
+                        }
+                    }
+                }
+            } else {
+                // Visit the child closer to the origin first by pushing it last
+                let second_child = node.offset as usize;
+                let (near, far) = if negative[node.axis as usize] {
+                    (second_child, node_idx + 1)
+                } else {
+                    (node_idx + 1, second_child)
+                };
+                stack.push(far);
+                stack.push(near);
+            }
+        }
+
+        closest
+    }
+
+    /// Finds the closest hits of the given packet of rays, given as origin, direction and
+    /// maximum ray parameter, like `closest_hit` for each of them.
+    ///
+    /// Traverses the hierarchy once, carrying along the rays of the packet that still
+    /// intersect the bounds of each node, so each node is visited once for all rays
+    /// passing through it instead of once per ray. Pays off for coherent packets, e.g. rays
+    /// with similar directions and nearby origins.
+    fn closest_hits(&self, rays: &[(Vec3, Vec3, f32)]) -> Vec<Option<(usize, f32)>> {
+        let mut closest: Vec<Option<(usize, f32)>> = vec![None; rays.len()];
+        if self.triangles.is_empty() || rays.is_empty() {
+            return closest;
+        }
+
+        let inv_directions: Vec<Vec3> = rays
+            .iter()
+            .map(|&(_, direction, _)| reciprocal(direction))
+            .collect();
+        let mut closest_t: Vec<f32> = rays.iter().map(|&(_, _, max_t)| max_t).collect();
+
+        // Indexes of the rays that are active in each node on the stack. Nodes refer to a
+        // range in here that ends before the ranges of nodes pushed after them start, so
+        // popping a node frees all ranges after its own.
+        let mut active: Vec<usize> = (0..rays.len()).collect();
+        let mut stack = Vec::with_capacity(64);
+        stack.push((0, 0, rays.len()));
+
+        while let Some((node_idx, start, end)) = stack.pop() {
+            active.truncate(end);
+            let node = &self.nodes[node_idx];
+
+            for active_idx in start..end {
+                let ray_idx = active[active_idx];
+                let origin = rays[ray_idx].0;
+                if intersects_box(node, origin, inv_directions[ray_idx], closest_t[ray_idx]) {
+                    active.push(ray_idx);
+                }
+            }
+
+            // Only the rays that hit the bounds of this node are active in its subtree
+            let (start, end) = (end, active.len());
+            if start == end {
+                continue;
+            }
+
+            if node.count > 0 {
+                let first = node.offset as usize;
+                for triangle in &self.triangles[first..(first + node.count as usize)] {
+                    for &ray_idx in &active[start..end] {
+                        let (origin, direction, _) = rays[ray_idx];
+                        if let Some(t) = intersect_triangle(triangle.positions, origin, direction) {
+                            if t <= closest_t[ray_idx] {
+                                closest_t[ray_idx] = t;
+                                closest[ray_idx] = Some((triangle.idx, t));
+                            }
+                        }
+                    }
+                }
+            } else {
+                // Visit the child closer to the origin of the first active ray first
+                let second_child = node.offset as usize;
+                let negative = inv_directions[active[start]][node.axis as usize] < 0.0;
+                let (near, far) = if negative {
+                    (second_child, node_idx + 1)
+                } else {
+                    (node_idx + 1, second_child)
+                };
+                stack.push((far, start, end));
+                stack.push((near, start, end));
+            }
+        }
+
+        closest
+    }
+}
+
+/// Appends the node for the given triangles and its children to the nodes in depth-first
+/// order and reorders the triangles so each leaf references a contiguous range.
+fn build_recursive(nodes: &mut Vec<Node>, triangles: &mut [BuildTriangle], first_idx: usize) {
+    let node_idx = nodes.len();
+    let (min, max) = triangles.iter().fold(
+        (
+            Vec3::new(INFINITY, INFINITY, INFINITY),
+            Vec3::new(NEG_INFINITY, NEG_INFINITY, NEG_INFINITY),
+        ),
+        |(min, max), t| (component_min(min, t.min), component_max(max, t.max)),
+    );
+    nodes.push(Node {
+        min,
+        max,
+        offset: first_idx as u32,
+        count: triangles.len() as u32,
+        axis: 0,
+    });
+
+    if triangles.len() <= MAX_LEAF_TRIANGLES {
+        return;
+    }
+
+    let split = match find_split(triangles, min, max) {
+        Some(split) => split,
+        None => return,
+    };
+    let (axis, mid) = split;
+
+    let (left, right) = triangles.split_at_mut(mid);
+    build_recursive(nodes, left, first_idx);
+    let second_child_idx = nodes.len();
+    build_recursive(nodes, right, first_idx + mid);
+
+    let node = &mut nodes[node_idx];
+    node.offset = second_child_idx as u32;
+    node.count = 0;
+    node.axis = axis as u8;
+}
+
+/// Finds the axis and the partition point with the lowest cost according to the surface
+/// area heuristic and partitions the triangles accordingly, or returns `None` if a leaf
+/// is cheaper than any split.
+fn find_split(triangles: &mut [BuildTriangle], min: Vec3, max: Vec3) -> Option<(usize, usize)> {
+    let (centroid_min, centroid_max) = triangles.iter().fold(
+        (
+            Vec3::new(INFINITY, INFINITY, INFINITY),
+            Vec3::new(NEG_INFINITY, NEG_INFINITY, NEG_INFINITY),
+        ),
+        |(min, max), t| {
+            (
+                component_min(min, t.centroid),
+                component_max(max, t.centroid),
+            )
+        },
+    );
+
+    let leaf_cost = triangles.len() as f32;
+    let parent_area = surface_area(min, max);
+    let mut best: Option<(usize, usize, f32)> = None;
+
+    for axis in 0..3 {
+        let extent = centroid_max[axis] - centroid_min[axis];
+        if extent <= EPSILON {
+            continue;
+        }
+
+        let mut buckets = [Bucket {
+            count: 0,
+            min: Vec3::new(INFINITY, INFINITY, INFINITY),
+            max: Vec3::new(NEG_INFINITY, NEG_INFINITY, NEG_INFINITY),
+        }; SAH_BUCKET_COUNT];
+        for t in triangles.iter() {
+            let bucket = &mut buckets[bucket_idx(t.centroid, axis, centroid_min, extent)];
+            bucket.count += 1;
+            bucket.min = component_min(bucket.min, t.min);
+            bucket.max = component_max(bucket.max, t.max);
+        }
+
+        // Split after each bucket except the last one
+        for split in 0..(SAH_BUCKET_COUNT - 1) {
+            let (left, right) = buckets.split_at(split + 1);
+            let (left_count, left_area) = merge_buckets(left);
+            let (right_count, right_area) = merge_buckets(right);
+            if left_count == 0 || right_count == 0 {
+                continue;
+            }
+
+            let cost = TRAVERSAL_COST
+                + (left_count as f32 * left_area + right_count as f32 * right_area) / parent_area;
+
+            if best.map_or(true, |(_, _, best_cost)| cost < best_cost) {
+                best = Some((axis, split, cost));
+            }
+        }
+    }
+
+    match best {
+        Some((axis, split, cost)) if cost < leaf_cost => {
+            let extent = centroid_max[axis] - centroid_min[axis];
+            let mid = partition(triangles, |t| {
+                bucket_idx(t.centroid, axis, centroid_min, extent) <= split
+            });
+            Some((axis, mid))
+        }
+        _ => None,
+    }
+}
+
+/// Index of the bucket along the given axis that the given centroid falls into.
+fn bucket_idx(centroid: Vec3, axis: usize, centroid_min: Vec3, extent: f32) -> usize {
+    let relative = (centroid[axis] - centroid_min[axis]) / extent;
+    ((relative * SAH_BUCKET_COUNT as f32) as usize).min(SAH_BUCKET_COUNT - 1)
+}
+
+/// Total triangle count and surface area of the bounds of the given buckets.
+fn merge_buckets(buckets: &[Bucket]) -> (usize, f32) {
+    let (count, min, max) = buckets.iter().fold(
+        (
+            0,
+            Vec3::new(INFINITY, INFINITY, INFINITY),
+            Vec3::new(NEG_INFINITY, NEG_INFINITY, NEG_INFINITY),
+        ),
+        |(count, min, max), b| {
+            (
+                count + b.count,
+                component_min(min, b.min),
+                component_max(max, b.max),
+            )
+        },
+    );
+
+    if count == 0 {
+        (0, 0.0)
+    } else {
+        (count, surface_area(min, max))
+    }
+}
+
+/// Moves the triangles that fulfill the predicate to the front and returns how many did.
+fn partition<F>(triangles: &mut [BuildTriangle], predicate: F) -> usize
+where
+    F: Fn(&BuildTriangle) -> bool,
+{
+    let mut mid = 0;
+    for idx in 0..triangles.len() {
+        if predicate(&triangles[idx]) {
+            triangles.swap(idx, mid);
+            mid += 1;
+        }
+    }
+    mid
+}
+
+/// Slab test of the ray against the bounds of the node, only counting intersections
+/// closer than `max_t`.
+fn intersects_box(node: &Node, origin: Vec3, inv_direction: Vec3, max_t: f32) -> bool {
+    let mut t_min = 0.0_f32;
+    let mut t_max = max_t;
+
+    for axis in 0..3 {
+        if inv_direction[axis].is_infinite() {
+            // Parallel to the slab, inside it or not at all. Checked explicitly, since
+            // origins on the slab boundary would multiply zero with infinity.
+            if origin[axis] < node.min[axis] || origin[axis] > node.max[axis] {
+                return false;
+            }
+            continue;
+        }
+
+        let t0 = (node.min[axis] - origin[axis]) * inv_direction[axis];
+        let t1 = (node.max[axis] - origin[axis]) * inv_direction[axis];
+        let (near, far) = if t0 <= t1 { (t0, t1) } else { (t1, t0) };
+        if near > t_min {
+            t_min = near;
+        }
+        if far < t_max {
+            t_max = far;
+        }
+        if t_min > t_max {
+            return false;
+        }
+    }
+
+    true
+}
+
+/// Möller–Trumbore intersection of a ray with a triangle from both sides, returning the
+/// ray parameter of the hit.
+fn intersect_triangle((a, b, c): (Vec3, Vec3, Vec3), origin: Vec3, direction: Vec3) -> Option<f32> {
+    let ab = b - a;
+    let ac = c - a;
+
+    let p = direction.cross(ac);
+    let det = ab.dot(p);
+    if det.abs() < EPSILON * EPSILON {
+        return None;
+    }
+    let inv_det = det.recip();
+
+    let ao = origin - a;
+    let u = ao.dot(p) * inv_det;
+    if u < 0.0 || u > 1.0 {
+        return None;
+    }
+
+    let q = ao.cross(ab);
+    let v = direction.dot(q) * inv_det;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+
+    let t = ac.dot(q) * inv_det;
+    if t >= 0.0 {
+        Some(t)
+    } else {
+        None
+    }
+}
+
+/// Component-wise reciprocal of the given direction, infinite for zero components.
+fn reciprocal(direction: Vec3) -> Vec3 {
+    Vec3::new(
+        direction.x.recip(),
+        direction.y.recip(),
+        direction.z.recip(),
+    )
+}
+
+fn surface_area(min: Vec3, max: Vec3) -> f32 {
+    let extent = max - min;
+    2.0 * (extent.x * extent.y + extent.y * extent.z + extent.z * extent.x)
+}
+
+fn component_min(a: Vec3, b: Vec3) -> Vec3 {
+    Vec3::new(a.x.min(b.x), a.y.min(b.y), a.z.min(b.z))
+}
+
+fn component_max(a: Vec3, b: Vec3) -> Vec3 {
+    Vec3::new(a.x.max(b.x), a.y.max(b.y), a.z.max(b.z))
+}
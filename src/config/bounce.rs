@@ -0,0 +1,25 @@
+use bounce::BounceModel;
+
+/// Assigns bounce models to entities.
+#[derive(Debug, Clone, Default)]
+pub struct Bounce {
+    /// Model for entities without a model of their own.
+    pub default: BounceModel,
+    /// Models indexed by entity index, `None` for entities using the default.
+    pub entities: Vec<Option<BounceModel>>,
+}
+
+impl Bounce {
+    /// Gets the bounce model for the entity with the given index.
+    pub fn model(&self, entity_idx: usize) -> BounceModel {
+        self.entities
+            .get(entity_idx)
+            .and_then(|model| *model)
+            .unwrap_or(self.default)
+    }
+
+    /// Whether any entity has a model of its own.
+    pub fn has_entity_models(&self) -> bool {
+        self.entities.iter().any(Option::is_some)
+    }
+}
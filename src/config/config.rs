@@ -1,7 +1,26 @@
-use config::Transport;
+use config::{Bounce, Dripping, Sides, Splashing, Tracing, Transport};
 
 /// Encapsulates parameters that influence substance transport and tracing.
 #[derive(Default)]
 pub struct Config {
     pub transport: Transport,
+    pub tracing: Tracing,
+    /// Distribution of directions for straight and parabolic bounces per entity.
+    pub bounce: Bounce,
+    /// Uses vertex normals interpolated at hit points for bounces, flow and surfel
+    /// selection instead of flat face normals.
+    pub smooth_normals: bool,
+    /// Only lets tons interact with surfels of the entity that was hit, so touching
+    /// entities do not pick up substances meant for their neighbours.
+    ///
+    /// Requires a simulation that knows the entity of each triangle, e.g. one created
+    /// with `Simulation::new_with_entities`. Otherwise, the entity of the surfel nearest
+    /// to the hit stands in for the hit entity, which may belong to a touching entity.
+    pub restrict_to_hit_entity: bool,
+    /// Whether tons bounce off the back of triangles per entity.
+    pub sides: Sides,
+    /// Lets flowing tons drip off edges instead of following gravity straight away.
+    pub dripping: Option<Dripping>,
+    /// Lets bouncing tons split into multiple tons on impact.
+    pub splashing: Option<Splashing>,
 }
@@ -0,0 +1,8 @@
+/// Lets flowing tons that lose contact with the surface hang at the edge for a while
+/// before dripping off on a ballistic path.
+#[derive(Debug, Clone)]
+pub struct Dripping {
+    /// Number of flow steps a ton hangs at an edge before it drips off. While hanging,
+    /// the ton keeps interacting with the surfels around it.
+    pub hang_steps: usize,
+}
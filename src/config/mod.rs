@@ -1,5 +1,15 @@
+mod bounce;
 mod config;
+mod dripping;
+mod sidedness;
+mod splashing;
+mod tracing;
 mod transport;
 
+pub use self::bounce::Bounce;
 pub use self::config::Config;
+pub use self::dripping::Dripping;
+pub use self::sidedness::{BackFace, Sidedness, Sides};
+pub use self::splashing::Splashing;
+pub use self::tracing::{Acceleration, FlowModel, Integration, Tracing, Wind};
 pub use self::transport::Transport;
@@ -0,0 +1,43 @@
+/// Determines how tons interact with the two sides of triangles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Sidedness {
+    /// Both sides are surfaces, tons bounce and flow on the side they hit.
+    TwoSided,
+    /// Only the side the normal points to is a surface, with the given behavior
+    /// for tons hitting the back side.
+    SingleSided(BackFace),
+}
+
+/// Behavior of tons hitting the back side of single-sided triangles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BackFace {
+    /// The ton continues its motion as if the triangle was not there.
+    PassThrough,
+    /// The ton interacts with the surface and then settles.
+    Absorb,
+}
+
+impl Default for Sidedness {
+    fn default() -> Self {
+        Sidedness::TwoSided
+    }
+}
+
+/// Assigns sidedness to entities.
+#[derive(Debug, Clone, Default)]
+pub struct Sides {
+    /// Sidedness of entities without a sidedness of their own.
+    pub default: Sidedness,
+    /// Sidedness indexed by entity index, `None` for entities using the default.
+    pub entities: Vec<Option<Sidedness>>,
+}
+
+impl Sides {
+    /// Gets the sidedness of the entity with the given index.
+    pub fn sidedness(&self, entity_idx: usize) -> Sidedness {
+        self.entities
+            .get(entity_idx)
+            .and_then(|sidedness| *sidedness)
+            .unwrap_or(self.default)
+    }
+}
@@ -0,0 +1,18 @@
+/// Lets tons that hit the surface fast or steeply split into child tons that share
+/// the weight of their parent.
+#[derive(Debug, Clone)]
+pub struct Splashing {
+    /// Number of child tons a splashing ton is split into.
+    pub children: usize,
+    /// Tons hitting the surface at least this fast splash. Only parabolic motion
+    /// models speed, straight and flowing tons always count as slow.
+    pub min_speed: Option<f32>,
+    /// Tons whose incoming direction has at least this cosine with the surface normal
+    /// splash, `1.0` meaning head-on.
+    pub min_incidence_cos: Option<f32>,
+    /// Half opening angle in radians of the cone around the mirror direction that
+    /// children are emitted in.
+    pub cone_angle: f32,
+    /// Tons that resulted from this many splashes in a row do not splash again.
+    pub max_generations: usize,
+}
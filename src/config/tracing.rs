@@ -0,0 +1,165 @@
+use geom::prelude::*;
+use geom::Vec3;
+use std::default::Default;
+use std::fmt;
+use std::sync::Arc;
+
+/// Determines how parabolic trajectories are advanced in time.
+#[derive(Debug, Clone, Copy)]
+pub enum Integration {
+    /// Explicit euler integration with the given timestep in seconds.
+    ///
+    /// Fast, but accumulates error with every step, so hits tend to be off
+    /// by some distance.
+    Euler { timestep: f32 },
+    /// Evaluates the closed form of the parabola every timestep and connects
+    /// the exact positions with line segments. When a segment hits a triangle,
+    /// the exact parabola is intersected with the plane of the triangle.
+    ///
+    /// Trajectories of tons with drag have no parabolic closed form and are
+    /// integrated with euler steps of the same timestep instead.
+    Analytic { timestep: f32 },
+}
+
+/// Determines how flowing tons move over the surface.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FlowModel {
+    /// Lifts the ton along the normal, then moves diagonally down towards the
+    /// expected target on a flat surface and follows gravity if the surface
+    /// was not found.
+    Diagonal,
+    /// Moves tangentially first, then drops back onto the surface against the
+    /// normal and follows gravity if the surface was not found, as described
+    /// in the thesis.
+    TangentialThenGravity,
+}
+
+/// Spatial data structure used to find intersections with the scene.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Acceleration {
+    /// Octree over the triangles of the scene.
+    Octree,
+    /// Bounding volume hierarchy built with the surface area heuristic, which is
+    /// typically faster for the many short segment queries of parabolic tracing
+    /// but takes longer to build.
+    Bvh,
+}
+
+/// Velocity of the air that airborne tons are dragged along with.
+#[derive(Clone)]
+pub enum Wind {
+    /// No wind at all.
+    Calm,
+    /// Same wind velocity everywhere.
+    Constant(Vec3),
+    /// Wind velocity grows with height above the ground following a power law,
+    /// with no wind below the ground. Height is measured against gravity.
+    Profile {
+        /// Wind velocity at the reference height.
+        velocity: Vec3,
+        /// Height above ground where the wind has the given velocity.
+        reference_height: f32,
+        /// Exponent of the power law, typically around `0.14` over open land.
+        exponent: f32,
+        /// Height of the ground, measured against gravity from the origin.
+        ground_height: f32,
+    },
+    /// Calculates wind velocity at the given position.
+    Field(Arc<dyn Fn(Vec3) -> Vec3 + Send + Sync>),
+}
+
+/// Parameters for the motion of tons between contacts.
+#[derive(Debug, Clone)]
+pub struct Tracing {
+    /// Data structure the tracer builds over the scene to find intersections.
+    pub acceleration: Acceleration,
+    /// Direction gravity pulls towards, defaults to negative y.
+    pub gravity_direction: Vec3,
+    /// Gravitational acceleration in units per second squared, must be positive.
+    pub gravity_magnitude: f32,
+    /// Method to advance parabolic trajectories.
+    pub integration: Integration,
+    /// Air movement that tons with drag are dragged along with on parabolic
+    /// trajectories.
+    pub wind: Wind,
+    /// Method to move flowing tons over the surface.
+    pub flow_model: FlowModel,
+    /// Multiple of the flow distance that flowing tons are lifted along the normal
+    /// before moving diagonally, or dropped against the normal after moving
+    /// tangentially. Determines how sharply surfaces may curve away and still
+    /// be followed.
+    pub flow_lift: f32,
+    /// Absolute distance that flowing tons search for the surface beyond the
+    /// distance expected on a flat surface, or beyond the flow lift for tons
+    /// dropping back onto the surface, so they stick to surfaces curving away.
+    /// Independent of the flow distance.
+    pub flow_adhesiveness: f32,
+    /// Seconds after which tons on parabolic trajectories stop if they have not hit
+    /// anything, so tracing over unbounded geometry terminates.
+    pub max_flight_time: f32,
+}
+
+impl Default for Tracing {
+    fn default() -> Self {
+        Tracing {
+            acceleration: Acceleration::Octree,
+            gravity_direction: Vec3::new(0.0, -1.0, 0.0),
+            gravity_magnitude: 9.81,
+            integration: Integration::Euler {
+                timestep: 1.0 / 60.0,
+            },
+            wind: Wind::Calm,
+            flow_model: FlowModel::Diagonal,
+            flow_lift: 1.3,
+            flow_adhesiveness: 1.1,
+            max_flight_time: 60.0,
+        }
+    }
+}
+
+impl Wind {
+    /// Gets the wind velocity at the given position, using the given up vector
+    /// to determine height for wind profiles.
+    pub fn velocity_at(&self, position: Vec3, up: Vec3) -> Vec3 {
+        match self {
+            &Wind::Calm => Vec3::new(0.0, 0.0, 0.0),
+            &Wind::Constant(velocity) => velocity,
+            &Wind::Profile {
+                velocity,
+                reference_height,
+                exponent,
+                ground_height,
+            } => {
+                let height = position.dot(up) - ground_height;
+                if height > 0.0 {
+                    velocity * (height / reference_height).powf(exponent)
+                } else {
+                    Vec3::new(0.0, 0.0, 0.0)
+                }
+            }
+            &Wind::Field(ref field) => field(position),
+        }
+    }
+}
+
+impl fmt::Debug for Wind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &Wind::Calm => write!(f, "Calm"),
+            &Wind::Constant(velocity) => write!(f, "Constant({:?})", velocity),
+            &Wind::Profile {
+                velocity,
+                reference_height,
+                exponent,
+                ground_height,
+            } => f
+                .debug_struct("Profile")
+                .field("velocity", &velocity)
+                .field("reference_height", &reference_height)
+                .field("exponent", &exponent)
+                .field("ground_height", &ground_height)
+                .finish(),
+            &Wind::Field(_) => write!(f, "Field(..)"),
+        }
+    }
+}
@@ -0,0 +1,58 @@
+use geom::prelude::*;
+use geom::Vec3;
+use std::f32::consts::PI;
+
+/// Finds two normalized vectors that form an orthonormal basis together with the given
+/// normalized vector.
+pub fn orthonormal_basis(normal: Vec3) -> (Vec3, Vec3) {
+    // Cross with the axis least aligned with the normal to avoid degenerate results
+    let helper = if normal.x.abs() < 0.9 {
+        Vec3::new(1.0, 0.0, 0.0)
+    } else {
+        Vec3::new(0.0, 1.0, 0.0)
+    };
+
+    let tangent = helper.cross(normal).normalize();
+    let bitangent = normal.cross(tangent);
+    (tangent, bitangent)
+}
+
+/// Transforms a direction from a local frame, where the z axis is the given normal, to world space.
+pub fn to_world(normal: Vec3, local: Vec3) -> Vec3 {
+    let (tangent, bitangent) = orthonormal_basis(normal);
+    local.x * tangent + local.y * bitangent + local.z * normal
+}
+
+/// Maps two numbers uniformly distributed in `[0, 1)` to a direction uniformly distributed
+/// in the cone around the given normalized axis, with the given half opening angle in radians.
+pub fn cone(axis: Vec3, half_angle: f32, u: f32, v: f32) -> Vec3 {
+    let cos_theta = 1.0 - u * (1.0 - half_angle.cos());
+    let sin_theta = (1.0 - cos_theta * cos_theta).max(0.0).sqrt();
+    let phi = 2.0 * PI * v;
+
+    to_world(
+        axis,
+        Vec3::new(sin_theta * phi.cos(), sin_theta * phi.sin(), cos_theta),
+    )
+}
+
+/// Maps two numbers uniformly distributed in `[0, 1)` to a direction in the hemisphere around
+/// the given normalized axis, distributed proportionally to the cosine with the axis.
+pub fn cosine_weighted(axis: Vec3, u: f32, v: f32) -> Vec3 {
+    let radius = u.sqrt();
+    let phi = 2.0 * PI * v;
+
+    to_world(
+        axis,
+        Vec3::new(
+            radius * phi.cos(),
+            radius * phi.sin(),
+            (1.0 - u).max(0.0).sqrt(),
+        ),
+    )
+}
+
+/// Mirrors the given direction on the plane with the given normalized normal.
+pub fn reflect(direction: Vec3, normal: Vec3) -> Vec3 {
+    direction - 2.0 * direction.dot(normal) * normal
+}
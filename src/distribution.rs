@@ -0,0 +1,42 @@
+use rand::distributions::{IndependentSample, Normal};
+use rand::Rng;
+
+/// Distribution of a ton parameter, sampled independently for each emitted ton.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Distribution {
+    /// Always the same value.
+    Constant(f32),
+    /// Uniformly distributed between the bounds.
+    Uniform { min: f32, max: f32 },
+    /// Normally distributed, with samples outside the bounds clamped to the bounds.
+    Normal {
+        mean: f32,
+        std_dev: f32,
+        min: f32,
+        max: f32,
+    },
+    /// One of the given values with equal probability, must not be empty.
+    Choice(Vec<f32>),
+}
+
+impl Distribution {
+    pub fn sample<R: Rng>(&self, rng: &mut R) -> f32 {
+        match self {
+            &Distribution::Constant(value) => value,
+            &Distribution::Uniform { min, max } => min + rng.gen::<f32>() * (max - min),
+            &Distribution::Normal {
+                mean,
+                std_dev,
+                min,
+                max,
+            } => {
+                let normal = Normal::new(mean as f64, std_dev as f64);
+                (normal.ind_sample(rng) as f32).max(min).min(max)
+            }
+            &Distribution::Choice(ref choices) => {
+                assert!(!choices.is_empty(), "Choice distribution without choices");
+                choices[rng.gen_range(0, choices.len())]
+            }
+        }
+    }
+}
@@ -0,0 +1,459 @@
+use bvh::Bvh;
+use config::Acceleration;
+use geom::prelude::*;
+use geom::{Aabb, Position, TupleTriangle, Vec3, Vertex};
+use primitive::Primitive;
+use spatial::Octree;
+use std::f32::INFINITY;
+
+/// Scene geometry that the tracer finds intersections with.
+///
+/// Implement this to trace against something other than the built-in acceleration
+/// structures, e.g. analytic test scenes, an external ray tracer or a mock that records
+/// queries. Geometry does not need to consist of triangles, intersections report the
+/// normal at the intersection point themselves.
+pub trait Geometry: Sync {
+    /// Finds the closest intersection of the ray with the scene, so that
+    /// `origin + t * direction` is the intersection point. The direction is not
+    /// necessarily normalized.
+    fn ray_intersection(&self, origin: Vec3, direction: Vec3) -> Option<Intersection>;
+
+    /// Finds the closest intersection of the line segment from the origin with the given
+    /// length along the normalized direction, returning the distance from the origin.
+    fn line_segment_intersection(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        length: f32,
+    ) -> Option<Intersection>;
+
+    /// Finds the closest intersections of many rays at once, in the order of the rays.
+    ///
+    /// The tracer passes groups of rays with similar directions and nearby origins.
+    /// Implement this to trace them together, e.g. as ray packets.
+    fn ray_intersections(&self, rays: &[(Vec3, Vec3)]) -> Vec<Option<Intersection>> {
+        rays.iter()
+            .map(|&(origin, direction)| self.ray_intersection(origin, direction))
+            .collect()
+    }
+
+    /// Finds the closest intersections of many line segments at once, given as origin,
+    /// normalized direction and length, in the order of the segments.
+    ///
+    /// The tracer passes groups of segments searched by flowing tons with similar
+    /// directions and nearby origins.
+    fn line_segment_intersections(
+        &self,
+        segments: &[(Vec3, Vec3, f32)],
+    ) -> Vec<Option<Intersection>> {
+        segments
+            .iter()
+            .map(|&(origin, direction, length)| {
+                self.line_segment_intersection(origin, direction, length)
+            })
+            .collect()
+    }
+
+    /// Gets the bounds of the scene, not including unbounded parts of it.
+    fn bounds(&self) -> Aabb;
+
+    /// Whether the bounds contain all of the scene. If not, e.g. for infinite planes,
+    /// tons on parabolic trajectories are traced beyond the bounds until they hit
+    /// something or the maximum flight time elapses.
+    fn is_bounded(&self) -> bool {
+        true
+    }
+
+    /// Gets the triangle with the given index, as reported by intersections, or `None`
+    /// if the element with the index is not a triangle.
+    ///
+    /// Triangles enable exact parabola intersections, interpolated vertex normals and
+    /// flow fields. Without them, the tracer intersects parabolas segment by segment and
+    /// uses the normals reported by the intersections.
+    fn triangle(&self, _idx: usize) -> Option<&TupleTriangle<Vertex>> {
+        None
+    }
+
+    /// Gets the index of the entity the element with the given index belongs to,
+    /// if known.
+    fn entity_idx(&self, _idx: usize) -> Option<usize> {
+        None
+    }
+
+    /// Whether `entity_idx` knows the entities of the elements covered with surfels.
+    fn has_entities(&self) -> bool {
+        false
+    }
+
+    /// Whether the element with the given index is covered with surfels, so that tons
+    /// hitting it exchange substances. Tons only bounce and flow off elements without
+    /// surfels, e.g. analytic primitives.
+    fn has_surfels(&self, _idx: usize) -> bool {
+        true
+    }
+}
+
+/// Closest intersection of a ray or line segment with the geometry.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Intersection {
+    /// Index of the triangle or other element of the geometry that was hit
+    pub idx: usize,
+    /// Ray parameter of the intersection, see the query methods of `Geometry`
+    pub t: f32,
+    /// Normalized geometric normal at the intersection point, telling the front face
+    /// from the back face
+    pub normal: Vec3,
+    /// Barycentric coordinates of the intersection point if a triangle was hit
+    pub barycentric: Option<Vec3>,
+}
+
+/// Triangles of the scene in an octree or a bounding volume hierarchy, the default
+/// geometry of the tracer.
+pub struct TriangleGeometry {
+    acceleration: AccelerationStructure,
+    /// Triangles in the order they were passed at construction
+    triangles: Vec<TupleTriangle<Vertex>>,
+    /// Entity index of each triangle, empty if constructed without entities
+    entity_indexes: Vec<usize>,
+}
+
+enum AccelerationStructure {
+    Octree(Octree<TupleTriangle<IndexedVertex>>),
+    Bvh(Bvh),
+}
+
+/// Vertex of a triangle in the octree, carrying the index of its triangle so hits on
+/// identical triangles can be told apart.
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct IndexedVertex {
+    position: Vec3,
+    triangle_idx: usize,
+}
+
+impl Position for IndexedVertex {
+    fn position(&self) -> Vec3 {
+        self.position
+    }
+}
+
+impl TriangleGeometry {
+    pub fn new<I>(triangles: I, acceleration: Acceleration) -> Self
+    where
+        I: IntoIterator<Item = TupleTriangle<Vertex>>,
+    {
+        Self::build(triangles.into_iter().collect(), Vec::new(), acceleration)
+    }
+
+    /// Creates geometry from triangles paired with the index of the entity they belong to.
+    pub fn new_with_entities<I>(triangles: I, acceleration: Acceleration) -> Self
+    where
+        I: IntoIterator<Item = (usize, TupleTriangle<Vertex>)>,
+    {
+        let (entity_indexes, triangles) = triangles.into_iter().unzip();
+        Self::build(triangles, entity_indexes, acceleration)
+    }
+
+    fn build(
+        triangles: Vec<TupleTriangle<Vertex>>,
+        entity_indexes: Vec<usize>,
+        acceleration: Acceleration,
+    ) -> Self {
+        let acceleration = match acceleration {
+            Acceleration::Octree => AccelerationStructure::Octree(
+                triangles
+                    .iter()
+                    .enumerate()
+                    .map(|(triangle_idx, triangle)| {
+                        let vertex = |position| IndexedVertex {
+                            position,
+                            triangle_idx,
+                        };
+                        let (a, b, c) = triangle.positions();
+                        TupleTriangle(vertex(a), vertex(b), vertex(c))
+                    })
+                    .collect(),
+            ),
+            Acceleration::Bvh => AccelerationStructure::Bvh(Bvh::new(&triangles)),
+        };
+
+        TriangleGeometry {
+            acceleration,
+            triangles,
+            entity_indexes,
+        }
+    }
+
+    /// Describes the intersection with the triangle with the given index at the given
+    /// ray parameter and point.
+    fn intersection(&self, idx: usize, t: f32, point: Vec3) -> Intersection {
+        let triangle = &self.triangles[idx];
+        Intersection {
+            idx,
+            t,
+            normal: triangle.normal(),
+            barycentric: Some(barycentric(triangle, point)),
+        }
+    }
+}
+
+impl Geometry for TriangleGeometry {
+    fn ray_intersection(&self, origin: Vec3, direction: Vec3) -> Option<Intersection> {
+        let closest = match &self.acceleration {
+            &AccelerationStructure::Octree(ref octree) => octree
+                .ray_intersection_target_and_parameter(origin, direction)
+                .map(|(triangle, t)| (triangle.0.triangle_idx, t)),
+            &AccelerationStructure::Bvh(ref bvh) => bvh.ray_intersection(origin, direction),
+        };
+        closest.map(|(idx, t)| self.intersection(idx, t, origin + t * direction))
+    }
+
+    fn line_segment_intersection(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        length: f32,
+    ) -> Option<Intersection> {
+        let closest = match &self.acceleration {
+            &AccelerationStructure::Octree(ref octree) => octree
+                .line_segment_intersection_target_and_parameter(origin, direction, length)
+                .map(|(triangle, t)| (triangle.0.triangle_idx, t)),
+            &AccelerationStructure::Bvh(ref bvh) => {
+                bvh.line_segment_intersection(origin, direction, length)
+            }
+        };
+        closest.map(|(idx, t)| self.intersection(idx, t, origin + t * direction))
+    }
+
+    /// Traces the rays as one packet through the hierarchy, or one after another through
+    /// the octree.
+    fn ray_intersections(&self, rays: &[(Vec3, Vec3)]) -> Vec<Option<Intersection>> {
+        match &self.acceleration {
+            &AccelerationStructure::Octree(_) => rays
+                .iter()
+                .map(|&(origin, direction)| self.ray_intersection(origin, direction))
+                .collect(),
+            &AccelerationStructure::Bvh(ref bvh) => bvh
+                .ray_intersections(rays)
+                .into_iter()
+                .zip(rays)
+                .map(|(closest, &(origin, direction))| {
+                    closest.map(|(idx, t)| self.intersection(idx, t, origin + t * direction))
+                })
+                .collect(),
+        }
+    }
+
+    /// Traces the segments as one packet through the hierarchy, or one after another
+    /// through the octree.
+    fn line_segment_intersections(
+        &self,
+        segments: &[(Vec3, Vec3, f32)],
+    ) -> Vec<Option<Intersection>> {
+        match &self.acceleration {
+            &AccelerationStructure::Octree(_) => segments
+                .iter()
+                .map(|&(origin, direction, length)| {
+                    self.line_segment_intersection(origin, direction, length)
+                })
+                .collect(),
+            &AccelerationStructure::Bvh(ref bvh) => bvh
+                .line_segment_intersections(segments)
+                .into_iter()
+                .zip(segments)
+                .map(|(closest, &(origin, direction, _))| {
+                    closest.map(|(idx, t)| self.intersection(idx, t, origin + t * direction))
+                })
+                .collect(),
+        }
+    }
+
+    fn bounds(&self) -> Aabb {
+        match &self.acceleration {
+            &AccelerationStructure::Octree(ref octree) => octree.bounds(),
+            &AccelerationStructure::Bvh(ref bvh) => bvh.bounds(),
+        }
+    }
+
+    fn triangle(&self, idx: usize) -> Option<&TupleTriangle<Vertex>> {
+        self.triangles.get(idx)
+    }
+
+    fn entity_idx(&self, idx: usize) -> Option<usize> {
+        self.entity_indexes.get(idx).cloned()
+    }
+
+    fn has_entities(&self) -> bool {
+        !self.entity_indexes.is_empty()
+    }
+}
+
+/// Triangles of the scene together with analytic primitives, e.g. an infinite ground plane.
+///
+/// Primitives are indexed after the triangles, in the order they were passed. They have
+/// no surfels and belong to no entity.
+pub struct CompositeGeometry {
+    triangles: TriangleGeometry,
+    primitives: Vec<Primitive>,
+}
+
+impl CompositeGeometry {
+    pub fn new(triangles: TriangleGeometry, primitives: Vec<Primitive>) -> Self {
+        CompositeGeometry {
+            triangles,
+            primitives,
+        }
+    }
+
+    /// Gets the index of the primitive with the given element index, or `None` for
+    /// triangles.
+    fn primitive_idx(&self, idx: usize) -> Option<usize> {
+        idx.checked_sub(self.triangles.triangles.len())
+    }
+
+    /// Finds the closest intersection of the ray with the primitives up to the given ray
+    /// parameter.
+    fn primitive_intersection(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        max_t: f32,
+    ) -> Option<Intersection> {
+        let first_idx = self.triangles.triangles.len();
+
+        self.primitives
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, primitive)| {
+                primitive
+                    .intersect(origin, direction, max_t)
+                    .map(|t| Intersection {
+                        idx: first_idx + idx,
+                        t,
+                        normal: primitive.normal_at(origin + t * direction),
+                        barycentric: None,
+                    })
+            })
+            .fold(None, |closer, intersection| {
+                closest(closer, Some(intersection))
+            })
+    }
+}
+
+impl Geometry for CompositeGeometry {
+    fn ray_intersection(&self, origin: Vec3, direction: Vec3) -> Option<Intersection> {
+        closest(
+            self.triangles.ray_intersection(origin, direction),
+            self.primitive_intersection(origin, direction, INFINITY),
+        )
+    }
+
+    fn line_segment_intersection(
+        &self,
+        origin: Vec3,
+        direction: Vec3,
+        length: f32,
+    ) -> Option<Intersection> {
+        closest(
+            self.triangles
+                .line_segment_intersection(origin, direction, length),
+            self.primitive_intersection(origin, direction, length),
+        )
+    }
+
+    fn ray_intersections(&self, rays: &[(Vec3, Vec3)]) -> Vec<Option<Intersection>> {
+        self.triangles
+            .ray_intersections(rays)
+            .into_iter()
+            .zip(rays)
+            .map(|(triangle, &(origin, direction))| {
+                closest(
+                    triangle,
+                    self.primitive_intersection(origin, direction, INFINITY),
+                )
+            })
+            .collect()
+    }
+
+    fn line_segment_intersections(
+        &self,
+        segments: &[(Vec3, Vec3, f32)],
+    ) -> Vec<Option<Intersection>> {
+        self.triangles
+            .line_segment_intersections(segments)
+            .into_iter()
+            .zip(segments)
+            .map(|(triangle, &(origin, direction, length))| {
+                closest(
+                    triangle,
+                    self.primitive_intersection(origin, direction, length),
+                )
+            })
+            .collect()
+    }
+
+    /// Gets the bounds of the triangles and the bounded primitives, not including
+    /// infinite planes.
+    fn bounds(&self) -> Aabb {
+        let mut bounds = self.triangles.bounds();
+
+        for (min, max) in self.primitives.iter().filter_map(Primitive::bounds) {
+            for axis in 0..3 {
+                bounds.min[axis] = bounds.min[axis].min(min[axis]);
+                bounds.max[axis] = bounds.max[axis].max(max[axis]);
+            }
+        }
+
+        bounds
+    }
+
+    fn is_bounded(&self) -> bool {
+        self.primitives.iter().all(|p| p.bounds().is_some())
+    }
+
+    fn triangle(&self, idx: usize) -> Option<&TupleTriangle<Vertex>> {
+        self.triangles.triangle(idx)
+    }
+
+    fn entity_idx(&self, idx: usize) -> Option<usize> {
+        match self.primitive_idx(idx) {
+            Some(_) => None,
+            None => self.triangles.entity_idx(idx),
+        }
+    }
+
+    fn has_entities(&self) -> bool {
+        self.triangles.has_entities()
+    }
+
+    fn has_surfels(&self, idx: usize) -> bool {
+        self.primitive_idx(idx).is_none()
+    }
+}
+
+/// Picks the closer of two intersections.
+fn closest(a: Option<Intersection>, b: Option<Intersection>) -> Option<Intersection> {
+    match (a, b) {
+        (Some(a), Some(b)) => Some(if a.t <= b.t { a } else { b }),
+        (a, None) => a,
+        (None, b) => b,
+    }
+}
+
+/// Calculates barycentric coordinates of a point in the plane of the given triangle.
+pub(crate) fn barycentric(triangle: &TupleTriangle<Vertex>, point: Vec3) -> Vec3 {
+    let (a, b, c) = triangle.positions();
+    let ab = b - a;
+    let ac = c - a;
+    let ap = point - a;
+
+    let d00 = ab.dot(ab);
+    let d01 = ab.dot(ac);
+    let d11 = ac.dot(ac);
+    let d20 = ap.dot(ab);
+    let d21 = ap.dot(ac);
+    let denom = d00 * d11 - d01 * d01;
+
+    let v = (d11 * d20 - d01 * d21) / denom;
+    let w = (d00 * d21 - d01 * d20) / denom;
+    Vec3::new(1.0 - v - w, v, w)
+}
@@ -9,20 +9,41 @@ extern crate rand;
 extern crate log;
 extern crate rayon;
 
+mod bounce;
+mod bvh;
 mod config;
+mod direction;
+mod distribution;
+mod geometry;
 mod motion;
+mod primitive;
 mod sim;
+mod stratify;
 mod surfel_data;
 mod surfel_rule;
+#[cfg(test)]
+mod test_fixtures;
 mod ton;
 mod tracer;
 mod transport;
 
-pub use config::{Config, Transport};
+pub use bounce::BounceModel;
+pub use config::{
+    Acceleration, BackFace, Bounce, Config, Dripping, FlowModel, Integration, Sidedness, Sides,
+    Splashing, Tracing, Transport, Wind,
+};
+pub use distribution::Distribution;
+pub use geometry::{CompositeGeometry, Geometry, Intersection, TriangleGeometry};
+pub use motion::{MotionMultipliers, MotionType, TransferMultipliers};
+pub use primitive::Primitive;
 pub use sim::Simulation;
+pub use stratify::{EmissionSampler, EmissionSampling};
 pub use surfel_data::SurfelData;
 pub use surfel_rule::SurfelRule;
-pub use ton::{TonSource, TonSourceBuilder};
+pub use ton::{
+    DomeDistribution, FlowField, TonSource, TonSourceBuilder, VertexSubstances, VertexWeight,
+    VolumeDirections,
+};
 
 #[cfg(feature = "export_tracer")]
 pub use tracer::*;
@@ -1,7 +1,56 @@
-#[derive(PartialEq, Copy, Clone)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum MotionType {
     Straight,
     Parabolic,
     Flow,
     Settled,
 }
+
+/// Factors applied to the pickup and deposition rates of a single contact.
+#[derive(Debug, Copy, Clone)]
+pub struct TransferMultipliers {
+    /// Multiplied with the pickup rates of the ton.
+    pub pickup: f32,
+    /// Multiplied with the deposition rates of the surfel.
+    pub deposition: f32,
+}
+
+/// Transfer multipliers for each motion type a ton can continue with
+/// after a contact, e.g. to let flowing tons exchange more substance
+/// with the surface than bouncing ones.
+#[derive(Debug, Clone, Default)]
+pub struct MotionMultipliers {
+    pub straight: TransferMultipliers,
+    pub parabolic: TransferMultipliers,
+    pub flow: TransferMultipliers,
+    pub settled: TransferMultipliers,
+}
+
+impl Default for TransferMultipliers {
+    fn default() -> Self {
+        TransferMultipliers {
+            pickup: 1.0,
+            deposition: 1.0,
+        }
+    }
+}
+
+impl MotionMultipliers {
+    pub fn get(&self, motion_type: MotionType) -> TransferMultipliers {
+        match motion_type {
+            MotionType::Straight => self.straight,
+            MotionType::Parabolic => self.parabolic,
+            MotionType::Flow => self.flow,
+            MotionType::Settled => self.settled,
+        }
+    }
+
+    pub fn set(&mut self, motion_type: MotionType, multipliers: TransferMultipliers) {
+        match motion_type {
+            MotionType::Straight => self.straight = multipliers,
+            MotionType::Parabolic => self.parabolic = multipliers,
+            MotionType::Flow => self.flow = multipliers,
+            MotionType::Settled => self.settled = multipliers,
+        }
+    }
+}
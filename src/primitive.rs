@@ -0,0 +1,186 @@
+use geom::prelude::*;
+use geom::Vec3;
+use std::f32::{EPSILON, INFINITY, NEG_INFINITY};
+
+/// Intersections closer than this distance to the origin of a query are ignored, so tons
+/// leaving a primitive do not hit it again right away.
+const SELF_INTERSECTION_DISTANCE: f32 = 0.0001;
+
+/// Analytic shape that tons can hit in addition to the triangles of the scene, see
+/// `CompositeGeometry`.
+///
+/// Primitives have no surfels, so tons hitting them do not exchange substances, but
+/// bounce and flow off them like off triangles. Primitives are not part of any entity
+/// and use the default settings for entities.
+#[derive(Debug, Clone, Copy)]
+pub enum Primitive {
+    /// Infinite plane through the point, e.g. a ground extending to the horizon.
+    /// The normal determines the front side of the plane.
+    Plane { point: Vec3, normal: Vec3 },
+    /// Sphere with outward normals.
+    Sphere { center: Vec3, radius: f32 },
+    /// Axis-aligned box with outward normals. Boxes that are flat along an axis face
+    /// along that axis.
+    Box { min: Vec3, max: Vec3 },
+}
+
+impl Primitive {
+    /// Finds the ray parameter of the closest intersection of the ray with the primitive,
+    /// with the intersection point at `origin + t * direction` and `t` at most `max_t`.
+    /// Rays starting inside spheres and boxes hit them from the inside.
+    pub fn intersect(&self, origin: Vec3, direction: Vec3, max_t: f32) -> Option<f32> {
+        let direction_len = direction.magnitude();
+        if direction_len == 0.0 {
+            return None;
+        }
+        let min_t = SELF_INTERSECTION_DISTANCE / direction_len;
+
+        let closest = |near: f32, far: f32| {
+            if near >= min_t {
+                Some(near)
+            } else if far >= min_t {
+                Some(far)
+            } else {
+                None
+            }
+        };
+
+        let t = match self {
+            &Primitive::Plane { point, normal } => {
+                let denom = normal.dot(direction);
+                if denom.abs() < EPSILON {
+                    None
+                } else {
+                    Some(normal.dot(point - origin) / denom).filter(|&t| t >= min_t)
+                }
+            }
+            &Primitive::Sphere { center, radius } => {
+                let to_origin = origin - center;
+                let a = direction.dot(direction);
+                let half_b = to_origin.dot(direction);
+                let c = to_origin.dot(to_origin) - radius * radius;
+                let discriminant = half_b * half_b - a * c;
+                if discriminant < 0.0 {
+                    None
+                } else {
+                    let root = discriminant.sqrt();
+                    closest((-half_b - root) / a, (-half_b + root) / a)
+                }
+            }
+            &Primitive::Box { min, max } => {
+                let mut near = NEG_INFINITY;
+                let mut far = INFINITY;
+                let mut missed = false;
+                for axis in 0..3 {
+                    if direction[axis].abs() < EPSILON {
+                        // Parallel to the slab, inside it or not at all
+                        if origin[axis] < min[axis] || origin[axis] > max[axis] {
+                            missed = true;
+                        }
+                    } else {
+                        let t0 = (min[axis] - origin[axis]) / direction[axis];
+                        let t1 = (max[axis] - origin[axis]) / direction[axis];
+                        near = near.max(t0.min(t1));
+                        far = far.min(t0.max(t1));
+                    }
+                }
+
+                if missed || near > far {
+                    None
+                } else {
+                    closest(near, far)
+                }
+            }
+        };
+
+        t.filter(|&t| t <= max_t)
+    }
+
+    /// Gets the normalized normal at a point on the surface of the primitive.
+    pub fn normal_at(&self, point: Vec3) -> Vec3 {
+        match self {
+            &Primitive::Plane { normal, .. } => normal.normalize(),
+            &Primitive::Sphere { center, .. } => (point - center).normalize(),
+            &Primitive::Box { min, max } => {
+                // Normal of the face the point is relatively closest to
+                let center = (min + max) * 0.5;
+                let half_extent = (max - min) * 0.5;
+                let relative = point - center;
+                let mut axis = 0;
+                let mut max_ratio = NEG_INFINITY;
+                for candidate in 0..3 {
+                    // Points on flat boxes are on the face of the flat axis
+                    let ratio = if half_extent[candidate] > 0.0 {
+                        (relative[candidate] / half_extent[candidate]).abs()
+                    } else {
+                        INFINITY
+                    };
+                    if ratio > max_ratio {
+                        max_ratio = ratio;
+                        axis = candidate;
+                    }
+                }
+
+                let mut normal = Vec3::new(0.0, 0.0, 0.0);
+                normal[axis] = if half_extent[axis] > 0.0 {
+                    relative[axis].signum()
+                } else {
+                    1.0
+                };
+                normal
+            }
+        }
+    }
+
+    /// Gets the bounds of the primitive as minimum and maximum, or `None` for unbounded
+    /// planes.
+    pub fn bounds(&self) -> Option<(Vec3, Vec3)> {
+        match self {
+            &Primitive::Plane { .. } => None,
+            &Primitive::Sphere { center, radius } => {
+                let extent = Vec3::new(radius, radius, radius);
+                Some((center - extent, center + extent))
+            }
+            &Primitive::Box { min, max } => Some((min, max)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_box_normals() {
+        let cube = Primitive::Box {
+            min: Vec3::new(-1.0, -1.0, -1.0),
+            max: Vec3::new(1.0, 1.0, 1.0),
+        };
+        assert_eq!(
+            cube.normal_at(Vec3::new(0.2, -1.0, 0.5)),
+            Vec3::new(0.0, -1.0, 0.0)
+        );
+        assert_eq!(
+            cube.normal_at(Vec3::new(1.0, 0.9, -0.3)),
+            Vec3::new(1.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    fn test_flat_box_normals() {
+        let flat = Primitive::Box {
+            min: Vec3::new(-1.0, 0.0, -1.0),
+            max: Vec3::new(1.0, 0.0, 1.0),
+        };
+
+        // Also close to the edges, where the other axes have the largest ratio
+        for &(x, z) in &[(0.0, 0.0), (0.99, 0.5), (-1.0, 1.0)] {
+            let point = Vec3::new(x, 0.0, z);
+            assert_eq!(flat.normal_at(point), Vec3::new(0.0, 1.0, 0.0));
+        }
+
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let t = flat.intersect(Vec3::new(0.5, 2.0, 0.5), down, INFINITY);
+        assert_eq!(t, Some(2.0));
+    }
+}
@@ -1,35 +1,91 @@
-use config::{Config, Transport::*};
+use bounce::BounceModel;
+use config::{BackFace, Config, Dripping, Sidedness, Splashing, Transport::*};
+use direction;
 use geom::prelude::*;
 use geom::{TangentSpace, TupleTriangle, Vec3, Vertex};
+use geometry::{Geometry, TriangleGeometry};
 use motion::MotionType;
 use rand;
 use rand::Rng;
 use rayon::prelude::*;
-use sampling::{Uniform, UnitHemisphere};
+use std::cmp::Ordering;
 use std::default::Default;
 use surf;
 use surf::Surfel;
 use surfel_data::SurfelData;
 use surfel_rule::SurfelRule;
 use ton::{FlowDirection, Ton, TonSource};
-use tracer::{Hit, Tracer};
+use tracer::{FlowQuery, FlowStep, Hit, Tracer, SELF_INTERSECTION_EPSILON};
 
 type Surface = surf::Surface<Surfel<Vertex, SurfelData>>;
 type Tri = TupleTriangle<Vertex>;
 
 // Cut tracing early after this many bounces and leak materials in the tons
 const MAX_BOUNCES: usize = 128;
+// Vectors with a squared sine of less than this relative to a plane count as perpendicular
+const PERPENDICULAR_EPSILON: f32 = 0.00000001;
+// Let tons interact with a back face after passing through this many in a row
+const MAX_PASS_THROUGHS: usize = 32;
+// Double the radius searched for the nearest surfel of the hit entity this many times
+// before scanning all surfels of the entity
+const NEAREST_ENTITY_SEARCH_STEPS: usize = 8;
 
-pub struct Simulation {
+/// A ton that hit the surface and is about to interact with it.
+struct Contact {
+    ton: Ton,
+    intersection_point: Vec3,
+    incoming_direction: Vec3,
+    /// Index of the triangle or other element of the geometry that was hit in the tracer
+    triangle_idx: usize,
+    /// Entity the triangle belongs to, if the tracer knows it
+    entity_idx: Option<usize>,
+    /// Whether the element that was hit has surfels to exchange substances with
+    has_surfels: bool,
+    /// Barycentric coordinates of the intersection point on the triangle, if a triangle
+    /// was hit
+    barycentric: Option<Vec3>,
+    /// Geometric normal of the triangle or primitive at the intersection point
+    face_normal: Vec3,
+    /// Whether the triangle was hit on the side its normal points to
+    front_face: bool,
+    /// Speed at impact, zero if the motion leading to the contact does not model speed
+    speed: f32,
+}
+
+/// How a ton moves on from a contact, before its motion is traced.
+enum Departure {
+    /// Bounces off in a straight line, traced in a batch.
+    Straight {
+        ton: Ton,
+        from: Vec3,
+        direction: Vec3,
+    },
+    /// Flows over the surface, traced in a batch.
+    Flow { ton: Ton, query: FlowQuery },
+    /// Flows over the surface and may hang on to the contact or drip off, with the flow
+    /// step traced in a batch.
+    Drip { hit: Contact, query: FlowQuery },
+    /// Motion that was already traced, e.g. parabolic bounces and splashes, with the point
+    /// where the ton dripped off, if it did.
+    Traced(Vec<Contact>, Option<Vec3>),
+}
+
+pub struct Simulation<G = TriangleGeometry> {
     config: Config,
     sources: Vec<TonSource>,
-    tracer: Tracer,
+    tracer: Tracer<G>,
     surface: Surface,
     /// Global surfel rules for all surfels
     surfel_rules: Vec<SurfelRule>,
+    /// Indexes of the surfels of each entity, indexed by entity
+    entity_surfels: Vec<Vec<usize>>,
+    /// Number of tons that dripped off near each surfel
+    drip_counts: Vec<usize>,
+    /// Fraction of the emissions of the last iteration that did not hit the scene
+    missed_emission_fraction: f32,
 }
 
-impl Simulation {
+impl Simulation<TriangleGeometry> {
     pub fn new_with_config<I>(
         config: Config,
         sources: Vec<TonSource>,
@@ -40,13 +96,25 @@ impl Simulation {
     where
         I: IntoIterator<Item = TupleTriangle<Vertex>>,
     {
-        Simulation {
-            config,
-            sources,
-            surface,
-            tracer: Tracer::new(triangles),
-            surfel_rules,
-        }
+        let tracer = Tracer::new_with_config(triangles, &config.tracing);
+        Self::with_tracer(config, sources, tracer, surface, surfel_rules)
+    }
+
+    /// Creates a simulation for triangles paired with the index of the entity they belong
+    /// to. Per-entity settings then apply to the entity of the triangle that was hit rather
+    /// than to the entity of the nearest surfel.
+    pub fn new_with_entities<I>(
+        config: Config,
+        sources: Vec<TonSource>,
+        triangles: I,
+        surface: Surface,
+        surfel_rules: Vec<SurfelRule>,
+    ) -> Self
+    where
+        I: IntoIterator<Item = (usize, TupleTriangle<Vertex>)>,
+    {
+        let tracer = Tracer::new_with_entities(triangles, &config.tracing);
+        Self::with_tracer(config, sources, tracer, surface, surfel_rules)
     }
 
     pub fn new<I>(
@@ -66,11 +134,65 @@ impl Simulation {
             surfel_rules,
         )
     }
+}
+
+impl<G: Geometry> Simulation<G> {
+    /// Creates a simulation that traces tons against custom geometry instead of the
+    /// triangles of the scene.
+    pub fn new_with_geometry(
+        config: Config,
+        sources: Vec<TonSource>,
+        geometry: G,
+        surface: Surface,
+        surfel_rules: Vec<SurfelRule>,
+    ) -> Self {
+        let tracer = Tracer::with_geometry(geometry, &config.tracing);
+        Self::with_tracer(config, sources, tracer, surface, surfel_rules)
+    }
+
+    fn with_tracer(
+        config: Config,
+        mut sources: Vec<TonSource>,
+        tracer: Tracer<G>,
+        surface: Surface,
+        surfel_rules: Vec<SurfelRule>,
+    ) -> Self {
+        if config.restrict_to_hit_entity && !tracer.has_entities() {
+            warn!("Restricting interaction to the hit entity, but the geometry does not know the entities of its triangles. Falling back to the entity of the nearest surfel, create the simulation with entities for exact results.");
+        }
+
+        // Keep sources that were explicitly fitted in the builder as they are
+        let bounds = tracer.bounds();
+        for source in sources.iter_mut().filter(|source| !source.is_fitted()) {
+            source.fit_to_bounds(&bounds);
+        }
+
+        let drip_counts = vec![0; surface.samples.len()];
+        let entity_surfels = entity_surfels(&surface);
+
+        Simulation {
+            config,
+            sources,
+            surface,
+            tracer,
+            surfel_rules,
+            entity_surfels,
+            drip_counts,
+            missed_emission_fraction: 0.0,
+        }
+    }
 
     // Advances the simulation by one iteration
     pub fn run(&mut self) {
         let mut hits = Self::initial_hits(&self.sources, &self.tracer);
 
+        let emission_count = self.emission_count();
+        self.missed_emission_fraction = if emission_count == 0 {
+            0.0
+        } else {
+            (emission_count - hits.len()) as f32 / emission_count as f32
+        };
+
         let mut bounces = 0;
         while bounces < MAX_BOUNCES && !hits.is_empty() {
             hits = self.trace_deepen(hits);
@@ -85,56 +207,103 @@ impl Simulation {
             )
         }
 
-        Self::perform_rules(&mut self.surface, &self.surfel_rules);
+        perform_rules(&mut self.surface, &self.surfel_rules);
     }
 
-    fn initial_hits(sources: &Vec<TonSource>, tracer: &Tracer) -> Vec<(Ton, Vec3, Vec3, Tri)> {
+    fn initial_hits(sources: &Vec<TonSource>, tracer: &Tracer<G>) -> Vec<Contact> {
         let emission_count = sources.iter().map(TonSource::emission_count).sum();
         let mut initial_hits = Vec::with_capacity(emission_count);
 
         // REVIEW does rayon give enough guarantees about ordering so nothing gets mixed up?
         for source in sources.iter() {
-            initial_hits.par_extend(
-                (0..source.emission_count())
-                    .into_par_iter()
-                    .map(|_| source.emit_one())
-                    .filter_map(|e| {
-                        tracer.trace_straight(e.origin, e.direction).map(|h| {
-                            (
-                                e.ton,
-                                h.intersection_point,
-                                h.incoming_direction,
-                                h.triangle.clone(),
-                            )
-                        })
-                    }),
-            )
+            let sampler = source.sampler();
+            let emissions: Vec<(Ton, Vec3, Vec3)> = (0..source.emission_count())
+                .into_par_iter()
+                .map(|idx| source.emit_nth(&sampler, idx))
+                // Discarded emissions, e.g. of unfitted sources or in directions the
+                // source never emits in, count as missed
+                .filter(|e| e.weight > 0.0)
+                .map(|e| {
+                    let direction = if source.wind_bias() == 0.0 {
+                        e.direction
+                    } else {
+                        let wind = tracer.wind_at(e.origin);
+                        wind_biased_direction(e.direction, source.wind_bias(), wind)
+                    };
+                    (e.ton, e.origin, direction)
+                })
+                .collect();
+
+            match source.initial_speed() {
+                Some(speed) => initial_hits.par_extend(emissions.into_par_iter().filter_map(
+                    |(ton, origin, direction)| {
+                        tracer
+                            .trace_ballistic(origin, speed * direction, ton.drag)
+                            .map(move |h| contact(ton, h, 0.0))
+                    },
+                )),
+                None => {
+                    // Emissions are traced in coherent batches
+                    let rays: Vec<(Vec3, Vec3)> = emissions
+                        .iter()
+                        .map(|&(_, origin, direction)| (origin, direction))
+                        .collect();
+                    let hits = tracer.trace_straight_batch(&rays);
+
+                    initial_hits.par_extend(
+                        emissions
+                            .into_par_iter()
+                            .zip(hits)
+                            .filter_map(|((ton, _, _), hit)| {
+                                hit.map(move |h| contact(ton, h, 0.0))
+                            }),
+                    )
+                }
+            }
         }
 
         initial_hits
     }
 
     /// Deepens the tracing another layer
-    fn trace_deepen(
-        &mut self,
-        mut hits: Vec<(Ton, Vec3, Vec3, Tri)>,
-    ) -> Vec<(Ton, Vec3, Vec3, Tri)> {
+    fn trace_deepen(&mut self, hits: Vec<Contact>) -> Vec<Contact> {
+        // Tons continue behind single-sided triangles they pass through before interacting
+        let mut hits: Vec<Contact> = hits
+            .into_par_iter()
+            .filter_map(|h| {
+                Self::pass_through_back_faces(&self.tracer, &self.config, &self.surface, h)
+            })
+            .collect();
+
         // Interaction selection can be parallel
         let interaction_info: Vec<(MotionType, Vec<usize>)> = hits
             .par_iter()
-            .map(|h| Self::select_interaction_idxs_and_next_motion_type(h, &self.surface))
+            .map(|h| {
+                Self::select_interaction_idxs_and_next_motion_type(
+                    &self.tracer,
+                    h,
+                    &self.surface,
+                    &self.entity_surfels,
+                    &self.config,
+                )
+            })
             .collect();
 
         // Deterioration can be parallel
         hits.par_iter_mut()
             .zip(interaction_info.par_iter())
             .for_each(|(ref mut hit, motion_and_idx)| {
-                Self::deteriorate_fast(hit, &motion_and_idx.1, &self.surface)
+                deteriorate_fast(hit, &motion_and_idx.1, &self.surface)
             });
 
-        // Sequentially exchange substances to avoid race condition
+        // Sequentially exchange substances to avoid race condition, primitives have no
+        // surfels to exchange with
         for (hit, interaction_info) in hits.iter_mut().zip(interaction_info.iter()) {
-            let (ref mut ton, _, _, _) = hit;
+            if interaction_info.1.is_empty() {
+                continue;
+            }
+
+            let ton = &mut hit.ton;
             let surfels = &mut self.surface.samples;
 
             match &self.config.transport {
@@ -145,72 +314,407 @@ impl Simulation {
             }
         }
 
-        // Move hits to next hit point, if any, and return new hits with settled
-        // tons filtered out.
-        hits.into_par_iter()
+        // Decide how tons move on in parallel
+        let departures: Vec<Departure> = hits
+            .into_par_iter()
             .zip(interaction_info)
-            .filter_map(
-                |((ton, intersection, incoming, triangle), (motion_type, _))| {
-                    Self::next_hit(
-                        &self.tracer,
-                        &ton,
-                        intersection,
-                        incoming,
-                        &triangle,
-                        motion_type,
-                    ).map(move |h| {
-                        (
-                            ton,
-                            h.intersection_point,
-                            h.incoming_direction,
-                            h.triangle.clone(),
-                        )
-                    })
-                },
-            )
+            .map(|(hit, (motion_type, _))| {
+                // Bounce off like the entity that was hit, only looking it up if
+                // entities bounce differently
+                let bounce = &self.config.bounce;
+                let bounce_model = if bounce.has_entity_models() {
+                    match entity_idx(&self.surface, &hit) {
+                        Some(entity_idx) => bounce.model(entity_idx),
+                        None => bounce.default,
+                    }
+                } else {
+                    bounce.default
+                };
+                Self::depart(&self.tracer, &self.config, hit, motion_type, bounce_model)
+            })
+            .collect();
+
+        // Move hits to next hit point, if any
+        let advanced = Self::advance(&self.tracer, &self.config, departures);
+
+        // Sequentially count drips and return new hits with settled tons filtered out
+        let mut next_hits = Vec::with_capacity(advanced.len());
+        for (next, drip_point) in advanced {
+            if let Some(drip_point) = drip_point {
+                self.drip_counts[self.surface.nearest_idx(drip_point)] += 1;
+            }
+
+            next_hits.extend(next);
+        }
+
+        next_hits
+    }
+
+    /// Decides how the ton moves on from the contact. Straight bounces and flow are left
+    /// for `advance` to trace in batches, other motion is traced right away.
+    fn depart(
+        tracer: &Tracer<G>,
+        config: &Config,
+        hit: Contact,
+        motion_type: MotionType,
+        bounce_model: BounceModel,
+    ) -> Departure {
+        let normal = Self::surface_normal(tracer, config, &hit);
+
+        match (motion_type, &config.splashing) {
+            (MotionType::Straight, &Some(ref splashing))
+            | (MotionType::Parabolic, &Some(ref splashing))
+                if splashes(splashing, &hit) =>
+            {
+                let children = Self::splash(tracer, splashing, hit, normal, motion_type);
+                Departure::Traced(children, None)
+            }
+            (MotionType::Straight, _) => {
+                let direction = bounce_direction(&hit, normal, bounce_model);
+                Departure::Straight {
+                    ton: hit.ton,
+                    from: hit.intersection_point,
+                    direction,
+                }
+            }
+            (MotionType::Parabolic, _) => {
+                // Bounce with the same distribution as in straight
+                let direction = bounce_direction(&hit, normal, bounce_model);
+                let ton = hit.ton;
+                let next = tracer
+                    .trace_parabolic_with_drag(
+                        hit.intersection_point,
+                        direction,
+                        ton.parabola_height,
+                        ton.drag,
+                    )
+                    .map(move |h| contact(ton, h, 0.0));
+                Departure::Traced(next.into_iter().collect(), None)
+            }
+            (MotionType::Flow, _) => {
+                let (up, tangential_direction) = Self::flow_direction(
+                    tracer,
+                    &hit.ton,
+                    hit.intersection_point,
+                    hit.incoming_direction,
+                    tracer.triangle(hit.triangle_idx),
+                    hit.face_normal,
+                    normal,
+                );
+                let query = FlowQuery {
+                    from: hit.intersection_point,
+                    up,
+                    tangential_direction,
+                    flow_distance: hit.ton.flow_distance,
+                };
+
+                if config.dripping.is_some() {
+                    Departure::Drip { hit, query }
+                } else {
+                    Departure::Flow {
+                        ton: hit.ton,
+                        query,
+                    }
+                }
+            }
+            (MotionType::Settled, _) => Departure::Traced(Vec::new(), None),
+        }
+    }
+
+    /// Moves the tons on to their next hits, which are multiple if a ton splashed and none
+    /// if it settled or left the scene. If a ton dripped off the surface, also returns
+    /// the point where it was hanging before dripping off.
+    ///
+    /// Straight bounces and flow steps are traced in coherent batches.
+    fn advance(
+        tracer: &Tracer<G>,
+        config: &Config,
+        departures: Vec<Departure>,
+    ) -> Vec<(Vec<Contact>, Option<Vec3>)> {
+        let mut rays = Vec::new();
+        let mut flow_queries = Vec::new();
+        let mut drip_queries = Vec::new();
+        for departure in departures.iter() {
+            match departure {
+                &Departure::Straight {
+                    from, direction, ..
+                } => rays.push((from, direction)),
+                &Departure::Flow { query, .. } => flow_queries.push(query),
+                &Departure::Drip { query, .. } => drip_queries.push(query),
+                &Departure::Traced(..) => (),
+            }
+        }
+
+        let mut straight_hits = tracer.trace_straight_batch(&rays).into_iter();
+        let mut flow_hits = tracer.trace_flow_batch(&flow_queries).into_iter();
+        let mut drip_steps = tracer.flow_step_batch(&drip_queries).into_iter();
+
+        // Batches were traced in departure order, so each departure takes the next result
+        departures
+            .into_iter()
+            .map(|departure| match departure {
+                Departure::Straight { ton, .. } => {
+                    let next = straight_hits.next().unwrap();
+                    (
+                        next.map(move |h| contact(ton, h, 0.0))
+                            .into_iter()
+                            .collect(),
+                        None,
+                    )
+                }
+                Departure::Flow { ton, .. } => {
+                    let next = flow_hits.next().unwrap();
+                    (
+                        next.map(move |h| contact(ton, h, 0.0))
+                            .into_iter()
+                            .collect(),
+                        None,
+                    )
+                }
+                Departure::Drip { hit, .. } => {
+                    let dripping = config.dripping.as_ref().unwrap();
+                    let step = drip_steps.next().unwrap();
+                    let (next, drip_point) = Self::hang_or_drip(tracer, dripping, hit, step);
+                    (next.into_iter().collect(), drip_point)
+                }
+                Departure::Traced(next, drip_point) => (next, drip_point),
+            })
             .collect()
     }
 
+    /// Moves a flowing ton on according to the outcome of its flow step, letting it hang
+    /// at an edge and drip off after the configured amount of flow steps. Returns the point
+    /// where the ton was hanging if it dripped off.
+    fn hang_or_drip(
+        tracer: &Tracer<G>,
+        dripping: &Dripping,
+        mut hit: Contact,
+        step: FlowStep,
+    ) -> (Option<Contact>, Option<Vec3>) {
+        match step {
+            FlowStep::Attached(h) => {
+                hit.ton.hanging_steps = 0;
+                (Some(contact(hit.ton, h, 0.0)), None)
+            }
+            FlowStep::Detached { .. } if hit.ton.hanging_steps < dripping.hang_steps => {
+                // Keep hanging at the edge, interacting with the surface again
+                // in the next iteration
+                hit.ton.hanging_steps += 1;
+                (Some(hit), None)
+            }
+            FlowStep::Detached { point, distance } => {
+                // Drip off, starting at rest
+                let Contact {
+                    mut ton,
+                    intersection_point,
+                    ..
+                } = hit;
+                ton.hanging_steps = 0;
+                let next = tracer
+                    .trace_parabolic_with_drag(point, tracer.gravity_direction(), 0.0, ton.drag)
+                    .map(move |h| contact(ton, h, distance));
+                (next, Some(intersection_point))
+            }
+        }
+    }
+
+    /// Splits the ton into child tons that share its weight and continue with the
+    /// same motion type in a cone around the mirror direction at the given surface
+    /// normal on the side of the hit.
+    ///
+    /// The bounce model is not consulted, since it describes where a whole ton goes
+    /// after a contact, while a splash sprays the children around the mirror direction
+    /// regardless of the surface. Children continue in the same tracing loop as their
+    /// parent and count against the same bounce limit.
+    fn splash(
+        tracer: &Tracer<G>,
+        splashing: &Splashing,
+        hit: Contact,
+        normal: Vec3,
+        motion_type: MotionType,
+    ) -> Vec<Contact> {
+        let Contact {
+            mut ton,
+            intersection_point,
+            incoming_direction,
+            face_normal,
+            ..
+        } = hit;
+
+        let mirror = direction::reflect(incoming_direction.normalize(), normal);
+
+        // Split the weight evenly, so the total amount of substance and transport is conserved
+        ton.weight /= splashing.children as f32;
+        ton.splash_generation += 1;
+
+        let mut rng = rand::thread_rng();
+        (0..splashing.children)
+            .filter_map(|_| {
+                let mut child_direction =
+                    direction::cone(mirror, splashing.cone_angle, rng.gen(), rng.gen());
+                // Wide cones may reach below the surface, mirror those back up, also out
+                // of the geometric surface if the normal is interpolated
+                if child_direction.dot(normal) < 0.0 {
+                    child_direction = direction::reflect(child_direction, normal);
+                }
+                let child_direction = keep_off_surface(face_normal, normal, child_direction);
+
+                let child = ton.clone();
+                let child_hit = match motion_type {
+                    MotionType::Parabolic => tracer.trace_parabolic_with_drag(
+                        intersection_point,
+                        child_direction,
+                        child.parabola_height,
+                        child.drag,
+                    ),
+                    _ => tracer.trace_straight(intersection_point, child_direction),
+                };
+
+                child_hit.map(move |h| contact(child, h, 0.0))
+            })
+            .collect()
+    }
+
+    /// Lets tons that hit the back of single-sided triangles configured to let them pass
+    /// continue their motion behind the triangle, until they hit a surface they interact
+    /// with or leave the scene.
+    fn pass_through_back_faces(
+        tracer: &Tracer<G>,
+        config: &Config,
+        surf: &Surface,
+        mut hit: Contact,
+    ) -> Option<Contact> {
+        for _ in 0..MAX_PASS_THROUGHS {
+            let passes = !hit.front_face
+                && sidedness(config, surf, &hit) == Sidedness::SingleSided(BackFace::PassThrough);
+            if !passes {
+                return Some(hit);
+            }
+
+            let Contact {
+                ton,
+                intersection_point,
+                incoming_direction,
+                speed,
+                ..
+            } = hit;
+            let direction = incoming_direction.normalize();
+
+            let (next, skipped) = if speed > 0.0 {
+                // Resume the parabola with the speed at impact
+                let height = speed * speed / (2.0 * config.tracing.gravity_magnitude);
+                let next = tracer.trace_parabolic_with_drag(
+                    intersection_point,
+                    direction,
+                    height,
+                    ton.drag,
+                );
+                (next, 0.0)
+            } else {
+                // Resume behind the triangle, far enough to not hit it again
+                let behind = intersection_point + direction * SELF_INTERSECTION_EPSILON;
+                (
+                    tracer.trace_straight(behind, direction),
+                    SELF_INTERSECTION_EPSILON,
+                )
+            };
+
+            hit = contact(ton, next?, skipped);
+        }
+
+        warn!(
+            "Ton passed through more than {} back faces, letting it interact with the last one.",
+            MAX_PASS_THROUGHS
+        );
+        Some(hit)
+    }
+
     fn select_interaction_idxs_and_next_motion_type(
-        (ton, intersection_point, _, hit_tri): &(Ton, Vec3, Vec3, Tri),
+        tracer: &Tracer<G>,
+        hit: &Contact,
         surf: &Surface,
+        entity_surfels: &[Vec<usize>],
+        config: &Config,
     ) -> (MotionType, Vec<usize>) {
+        let ton = &hit.ton;
+        let intersection_point = hit.intersection_point;
+
+        // Primitives have no surfels, tons only move on
+        if !hit.has_surfels {
+            return (select_motion_type(ton), Vec::new());
+        }
+
         let mut interaction_info =
-            surf.find_within_sphere_indexes(*intersection_point, ton.interaction_radius);
+            surf.find_within_sphere_indexes(intersection_point, ton.interaction_radius);
 
         // Throw out all surfels where normals are rotated by more than 90°
-        // relative to the normal of the hit triangle.
+        // relative to the surface normal at the hit point.
         // This aims to minimize surfels from the other side of thin surfaces,
         // being affected from hits to the other side.
         // Depending on the interaction radius and the complexity of the
         // surface in the interaction radius range, bleeding may still occur.
-        let hit_tri_normal = hit_tri.normal();
+        let hit_normal = Self::surface_normal(tracer, config, hit);
         interaction_info.retain(|&i| {
             let surfel_normal = surf.samples[i].vertex().normal;
-            hit_tri_normal.dot(surfel_normal) > 0.0
+            hit_normal.dot(surfel_normal) > 0.0
         });
 
+        // Keep surfels of touching entities from picking up substances for the hit entity
+        let hit_entity = if config.restrict_to_hit_entity {
+            let entity_idx = entity_idx(surf, hit).unwrap();
+            interaction_info.retain(|&i| surf.samples[i].data().entity_idx == entity_idx);
+            entity_surfels
+                .get(entity_idx)
+                .filter(|surfels| !surfels.is_empty())
+                .map(|surfels| (entity_idx, surfels))
+        } else {
+            None
+        };
+
         if interaction_info.len() == 0 {
             debug!("Ton hit a surface but did not interact with any surfels, try higher interaction radius, interacting with nearest surfel instead.");
-            interaction_info.push(surf.nearest_idx(*intersection_point));
+            let nearest_idx = match hit_entity {
+                Some((entity_idx, surfels)) => Some(nearest_of_entity(
+                    surf,
+                    entity_idx,
+                    surfels,
+                    intersection_point,
+                    ton.interaction_radius,
+                )),
+                // The hit entity has no surfels, and the ones of other entities are off limits
+                None if config.restrict_to_hit_entity => None,
+                None => Some(surf.nearest_idx(intersection_point)),
+            };
+            interaction_info.extend(nearest_idx);
         }
 
-        // FIXME the randomness depends on order, maybe re-seed here
-        (Self::select_motion_type(ton), interaction_info)
-    }
+        // Single-sided triangles may absorb tons hitting their back
+        let absorbed = !hit.front_face
+            && sidedness(config, surf, hit) == Sidedness::SingleSided(BackFace::Absorb);
+        if absorbed {
+            return (MotionType::Settled, interaction_info);
+        }
 
-    fn deteriorate_fast(
-        (ref mut ton, _, _, _): &mut (Ton, Vec3, Vec3, Tri),
-        surfel_idxs: &[usize],
-        surf: &Surface,
-    ) {
-        Self::deteriorate(ton, surf.samples[surfel_idxs[0]].data());
+        // FIXME the randomness depends on order, maybe re-seed here
+        (select_motion_type(ton), interaction_info)
     }
 
     pub fn surface(&self) -> &Surface {
         &self.surface
     }
 
+    /// Number of times a ton dripped off the surface near each surfel, indexed like the
+    /// surfels of the surface. Only counted if dripping is configured.
+    pub fn drip_counts(&self) -> &[usize] {
+        &self.drip_counts
+    }
+
+    /// Fraction of the tons emitted in the last iteration that missed the scene
+    /// and were discarded.
+    pub fn missed_emission_fraction(&self) -> f32 {
+        self.missed_emission_fraction
+    }
+
     pub fn surfel_count(&self) -> usize {
         self.surface.samples.len()
     }
@@ -220,143 +724,1151 @@ impl Simulation {
         self.sources.iter().map(|s| s.emission_count()).sum()
     }
 
-    fn perform_rules(surf: &mut Surface, global_rules: &Vec<SurfelRule>) {
-        // First the global rules
-        for rule in global_rules {
-            surf.samples
-                .iter_mut()
-                .for_each(|s| Self::perform_rule(&mut s.data_mut().substances, rule))
+    /// Determines the up vector and the normalized tangential direction for a flowing ton.
+    fn flow_direction(
+        tracer: &Tracer<G>,
+        ton: &Ton,
+        intersection_point: Vec3,
+        incoming_direction: Vec3,
+        triangle: Option<&Tri>,
+        face_normal: Vec3,
+        normal: Vec3,
+    ) -> (Vec3, Vec3) {
+        let up = normal;
+        let preferred_direction = match &ton.flow_direction {
+            &FlowDirection::Incident => incoming_direction,
+            &FlowDirection::Static(global_flow_direction) => global_flow_direction,
+            &FlowDirection::Gravity => tracer.gravity_direction(),
+            &FlowDirection::Field(ref field) => match triangle {
+                Some(triangle) => field.direction_at(triangle, intersection_point),
+                // Fields are defined on triangles, flow with gravity over other surfaces
+                None => tracer.gravity_direction(),
+            },
+        };
+
+        // If the preferred direction is perpendicular to the surface, e.g. gravity
+        // on a horizontal surface, fall back to the incoming direction, and if that
+        // is perpendicular too, to the tangent of the triangle or any tangent of other surfaces.
+        let flow_direction = project_onto_tangential_plane(up, preferred_direction)
+            .or_else(|| project_onto_tangential_plane(up, incoming_direction))
+            .unwrap_or_else(|| match triangle {
+                Some(triangle) => triangle.tangent(),
+                None => direction::orthonormal_basis(face_normal).0,
+            });
+
+        // Interpolated normals may tilt the flow direction into the geometric surface
+        let face_normal = if face_normal.dot(up) < 0.0 {
+            -face_normal
+        } else {
+            face_normal
+        };
+        let flow_direction = if flow_direction.dot(face_normal) < 0.0 {
+            project_onto_tangential_plane(face_normal, flow_direction).unwrap_or(flow_direction)
+        } else {
+            flow_direction
+        };
+
+        (up, flow_direction)
+    }
+
+    /// Normal of the surface at the contact on the side that was hit, which is the face
+    /// normal, or the vertex normal interpolated at the intersection point if smooth normals
+    /// are enabled.
+    ///
+    /// Falls back to the face normal if no triangle was hit, or if the interpolated normal is
+    /// degenerate or faces the other side of the triangle.
+    fn surface_normal(tracer: &Tracer<G>, config: &Config, hit: &Contact) -> Vec3 {
+        let normal = Self::front_surface_normal(tracer, config, hit);
+        if hit.front_face {
+            normal
+        } else {
+            -normal
         }
+    }
 
-        // Then the local ones
-        surf.samples.iter_mut().for_each(|s| {
-            let s = s.data_mut();
-            let substances = &mut s.substances;
-            for rule in &s.rules {
-                Self::perform_rule(substances, rule);
-            }
-        });
+    fn front_surface_normal(tracer: &Tracer<G>, config: &Config, hit: &Contact) -> Vec3 {
+        let face_normal = hit.face_normal;
+        if !config.smooth_normals {
+            return face_normal;
+        }
+
+        // Only triangles have vertex normals to interpolate
+        let triangle = tracer.triangle(hit.triangle_idx);
+        let (triangle, weights) = match (triangle, hit.barycentric) {
+            (Some(triangle), Some(weights)) => (triangle, weights),
+            _ => return face_normal,
+        };
+
+        let interpolated = triangle.0.normal * weights.x
+            + triangle.1.normal * weights.y
+            + triangle.2.normal * weights.z;
+
+        if interpolated.dot(face_normal) > 0.0 {
+            interpolated.normalize()
+        } else {
+            face_normal
+        }
     }
+}
 
-    fn perform_rule(substances: &mut Vec<f32>, rule: &SurfelRule) {
-        // REVIEW should the substances be clamped?
-        match rule {
-            &SurfelRule::Deteriorate {
-                substance_idx,
-                factor,
-            } => substances[substance_idx] = ((1.0 + factor) * substances[substance_idx]).max(0.0),
-
-            &SurfelRule::Transfer {
-                source_substance_idx,
-                target_substance_idx,
-                factor,
-            } => {
-                let transport_amount = factor * substances[source_substance_idx];
-                substances[source_substance_idx] =
-                    (substances[source_substance_idx] - transport_amount).max(0.0);
-                substances[target_substance_idx] =
-                    (substances[target_substance_idx] + transport_amount).max(0.0);
-            },
+/// Groups the surfel indexes by the entity the surfels belong to.
+fn entity_surfels(surface: &Surface) -> Vec<Vec<usize>> {
+    let mut entity_surfels: Vec<Vec<usize>> = Vec::new();
+    for (idx, surfel) in surface.samples.iter().enumerate() {
+        let entity_idx = surfel.data().entity_idx;
+        if entity_surfels.len() <= entity_idx {
+            entity_surfels.resize(entity_idx + 1, Vec::new());
+        }
+        entity_surfels[entity_idx].push(idx);
+    }
+    entity_surfels
+}
+
+fn splashes(splashing: &Splashing, hit: &Contact) -> bool {
+    if splashing.children == 0 || hit.ton.splash_generation >= splashing.max_generations {
+        return false;
+    }
+
+    let fast = splashing
+        .min_speed
+        .map_or(false, |min_speed| hit.speed >= min_speed);
 
-            &SurfelRule::Deposit {
-                substance_idx,
-                amount
-            } => substances[substance_idx] += amount,
+    let incidence_cos = hit
+        .incoming_direction
+        .normalize()
+        .dot(hit.face_normal)
+        .abs();
+    let steep = splashing
+        .min_incidence_cos
+        .map_or(false, |min_cos| incidence_cos >= min_cos);
+
+    fast || steep
+}
+
+/// Adds the given wind velocity scaled with the given bias to the emission direction
+/// and normalizes the sum, or keeps the emission direction if the wind cancels it out.
+fn wind_biased_direction(direction: Vec3, wind_bias: f32, wind: Vec3) -> Vec3 {
+    let biased = direction + wind_bias * wind;
+    if biased.magnitude2() > PERPENDICULAR_EPSILON {
+        biased.normalize()
+    } else {
+        direction
+    }
+}
+
+/// Turns a hit into a contact of the given ton with the surface, accounting for the
+/// distance travelled before the hit trace and during the hit trace.
+fn contact(mut ton: Ton, hit: Hit, distance_before: f32) -> Contact {
+    travel(&mut ton, distance_before + hit.distance);
+
+    Contact {
+        ton,
+        intersection_point: hit.intersection_point,
+        incoming_direction: hit.incoming_direction,
+        triangle_idx: hit.triangle_idx,
+        entity_idx: hit.entity_idx,
+        has_surfels: hit.has_surfels,
+        barycentric: hit.barycentric,
+        face_normal: hit.normal,
+        front_face: hit.front_face,
+        speed: hit.speed,
+    }
+}
+
+/// Sidedness of the entity that was hit, or the default sidedness for primitives.
+fn sidedness(config: &Config, surf: &Surface, hit: &Contact) -> Sidedness {
+    match entity_idx(surf, hit) {
+        Some(entity_idx) => config.sides.sidedness(entity_idx),
+        None => config.sides.default,
+    }
+}
+
+/// Entity of the triangle that was hit, or if the tracer does not know the entities
+/// of its triangles, the entity of the surfel nearest to the contact. `None` for
+/// elements without surfels, e.g. primitives, which belong to no entity.
+fn entity_idx(surf: &Surface, hit: &Contact) -> Option<usize> {
+    if !hit.has_surfels {
+        return None;
+    }
+    Some(hit.entity_idx.unwrap_or_else(|| {
+        let nearest_idx = surf.nearest_idx(hit.intersection_point);
+        surf.samples[nearest_idx].data().entity_idx
+    }))
+}
+
+/// Index of the surfel of the given entity nearest to the given point, out of the
+/// non-empty surfel indexes of the entity.
+///
+/// Searches spheres of doubling radius, starting with the given radius, until one
+/// contains a surfel of the entity. The nearest surfel of the entity is then inside
+/// the sphere too. Only scans all surfels of the entity if the entity is far away.
+fn nearest_of_entity(
+    surf: &Surface,
+    entity_idx: usize,
+    entity_surfel_idxs: &[usize],
+    point: Vec3,
+    radius: f32,
+) -> usize {
+    let mut radius = radius.max(::std::f32::EPSILON);
+    for _ in 0..NEAREST_ENTITY_SEARCH_STEPS {
+        let of_entity = surf
+            .find_within_sphere_indexes(point, radius)
+            .into_iter()
+            .filter(|&i| surf.samples[i].data().entity_idx == entity_idx);
+        if let Some(idx) = nearest_of(surf, of_entity, point) {
+            return idx;
         }
+        radius *= 2.0;
     }
 
-    fn next_hit<'a, 'b, 'c>(
-        tracer: &'a Tracer,
-        ton: &'b Ton,
-        intersection_point: Vec3,
-        incoming_direction: Vec3,
-        triangle: &'c Tri,
-        motion_type: MotionType,
-    ) -> Option<Hit<'a>> {
-        match motion_type {
-            MotionType::Straight => {
-                // Regard surface as completely diffuse:
-                // Uniformly sample upper Z hemisphere and transform to world space
-                // by multiplying with TBN matrix of triangle.
-                // This assumes CCW winding order for all triangles since normals, binormals
-                // and tangents are calculated from vertices.
-                let outgoing_world =
-                    triangle.tangent_to_world_matrix() * UnitHemisphere::PosZ.uniform();
-                tracer.trace_straight(intersection_point, outgoing_world)
-            }
-            MotionType::Parabolic => {
-                // Also sample diffuse just like in straight
-                let outgoing_world =
-                    triangle.tangent_to_world_matrix() * UnitHemisphere::PosZ.uniform();
-                tracer.trace_parabolic(intersection_point, outgoing_world, ton.parabola_height)
-            }
-            MotionType::Flow => {
-                let up = triangle.normal();
-                // TODO project Y unit vector (configurable)
-                // REVIEW maybe project_onto_tangential_plane should return an option for the perpendicular case
-                let mut flow_direction =
-                    triangle.project_onto_tangential_plane(match &ton.flow_direction {
-                        &FlowDirection::Incident => incoming_direction,
-                        &FlowDirection::Static(global_flow_direction) => global_flow_direction,
-                    });
-
-                // If projected flow direction is zero, it is the result of a failed attempt to project
-                // a perpendicular incoming direction, as a fallback, just project a diffusely sampled
-                // vector (until a non-zero projection turns up)
-                if flow_direction.is_zero() {
-                    flow_direction = loop {
-                        let fallback_flow_direction =
-                            triangle.project_onto_tangential_plane(triangle.uniform());
-                        if !fallback_flow_direction.is_zero() {
-                            break fallback_flow_direction;
-                        }
-                    };
-                }
+    nearest_of(surf, entity_surfel_idxs.iter().cloned(), point).unwrap()
+}
+
+/// Index of the surfel nearest to the given point out of the given surfel indexes.
+fn nearest_of<I>(surf: &Surface, surfel_idxs: I, point: Vec3) -> Option<usize>
+where
+    I: Iterator<Item = usize>,
+{
+    surfel_idxs.min_by(|&a, &b| {
+        let dist_a = (surf.samples[a].vertex().position - point).magnitude2();
+        let dist_b = (surf.samples[b].vertex().position - point).magnitude2();
+        dist_a.partial_cmp(&dist_b).unwrap_or(Ordering::Equal)
+    })
+}
+
+fn deteriorate_fast(hit: &mut Contact, surfel_idxs: &[usize], surf: &Surface) {
+    if let Some(&surfel_idx) = surfel_idxs.first() {
+        deteriorate(&mut hit.ton, surf.samples[surfel_idx].data());
+    }
+}
+
+fn perform_rules(surf: &mut Surface, global_rules: &Vec<SurfelRule>) {
+    // First the global rules
+    for rule in global_rules {
+        surf.samples
+            .iter_mut()
+            .for_each(|s| perform_rule(&mut s.data_mut().substances, rule))
+    }
+
+    // Then the local ones
+    surf.samples.iter_mut().for_each(|s| {
+        let s = s.data_mut();
+        let substances = &mut s.substances;
+        for rule in &s.rules {
+            perform_rule(substances, rule);
+        }
+    });
+}
+
+fn perform_rule(substances: &mut Vec<f32>, rule: &SurfelRule) {
+    // REVIEW should the substances be clamped?
+    match rule {
+        &SurfelRule::Deteriorate {
+            substance_idx,
+            factor,
+        } => substances[substance_idx] = ((1.0 + factor) * substances[substance_idx]).max(0.0),
+
+        &SurfelRule::Transfer {
+            source_substance_idx,
+            target_substance_idx,
+            factor,
+        } => {
+            let transport_amount = factor * substances[source_substance_idx];
+            substances[source_substance_idx] =
+                (substances[source_substance_idx] - transport_amount).max(0.0);
+            substances[target_substance_idx] =
+                (substances[target_substance_idx] + transport_amount).max(0.0);
+        },
+
+        &SurfelRule::Deposit {
+            substance_idx,
+            amount
+        } => substances[substance_idx] += amount,
+    }
+}
+
+/// Samples the direction of a straight or parabolic bounce around the normal on the side
+/// of the triangle that was hit, which relies on CCW winding order to tell the sides
+/// apart.
+fn bounce_direction(hit: &Contact, normal: Vec3, bounce_model: BounceModel) -> Vec3 {
+    keep_off_surface(
+        hit.face_normal,
+        normal,
+        bounce_model.sample(normal, hit.incoming_direction),
+    )
+}
+
+/// Mirrors a direction sampled around a possibly interpolated normal back out of the
+/// geometric surface if it points into it, on the side of the surface the direction
+/// was sampled for.
+fn keep_off_surface(face_normal: Vec3, normal: Vec3, direction: Vec3) -> Vec3 {
+    let face_normal = if face_normal.dot(normal) < 0.0 {
+        -face_normal
+    } else {
+        face_normal
+    };
+    let side_normal = if direction.dot(normal) >= 0.0 {
+        face_normal
+    } else {
+        -face_normal
+    };
+
+    if direction.dot(side_normal) < 0.0 {
+        direction::reflect(direction, side_normal)
+    } else {
+        direction
+    }
+}
+
+/// Projects the given vector onto the plane with the given normal and normalizes it,
+/// or returns `None` if the vector is perpendicular to the plane.
+fn project_onto_tangential_plane(normal: Vec3, vector: Vec3) -> Option<Vec3> {
+    let projected = vector - normal * vector.dot(normal);
+    let projected_len_sqr = projected.magnitude2();
+
+    if projected_len_sqr > PERPENDICULAR_EPSILON * vector.magnitude2() {
+        Some(projected / projected_len_sqr.sqrt())
+    } else {
+        None
+    }
+}
+
+fn select_motion_type(ton: &Ton) -> MotionType {
+    if ton.path_length >= ton.max_path_length {
+        return MotionType::Settled;
+    }
+
+    // Tons hanging at an edge keep flowing until they drip off
+    if ton.hanging_steps > 0 {
+        return MotionType::Flow;
+    }
+
+    let mut rng = rand::thread_rng();
+    let random: f32 = rng.gen();
+
+    let &Ton {
+        p_straight,
+        p_parabolic,
+        p_flow,
+        ..
+    } = ton;
+
+    if random < p_straight {
+        MotionType::Straight
+    } else if random < (p_straight + p_parabolic) {
+        MotionType::Parabolic
+    } else if random < (p_straight + p_parabolic + p_flow) {
+        MotionType::Flow
+    } else {
+        MotionType::Settled
+    }
+}
 
-                tracer.trace_flow(intersection_point, up, flow_direction, ton.flow_distance)
+/// Accounts for the given distance travelled by the ton since the last contact,
+/// evaporating substances along the way.
+fn travel(ton: &mut Ton, distance: f32) {
+    ton.path_length += distance;
+
+    // Exponential decay, so evaporation does not depend on how the path
+    // is subdivided into segments
+    for (substance, rate) in ton.substances.iter_mut().zip(ton.evaporation_rates.iter()) {
+        *substance *= (-rate * distance).exp();
+    }
+}
+
+fn deteriorate(ton: &mut Ton, surfel: &SurfelData) {
+    ton.p_straight = (ton.p_straight - surfel.delta_straight).max(0.0);
+    ton.p_parabolic = (ton.p_parabolic - surfel.delta_parabolic).max(0.0);
+    ton.p_flow = (ton.p_flow + ton.p_parabolic - surfel.delta_flow).max(0.0);
+
+    // REVIEW why this equation for flow? why doesn't it deteriorate like the others
+    //        the sum could be larger than 1.0
+    //
+    // Alternative (sane?) version:
+    // ton.p_flow = (ton.p_flow - surfel.delta_flow).max(0.0);
+
+    if (ton.p_straight + ton.p_parabolic + ton.p_flow) > 1.0 {
+        warn!("WARN: The flow equation from Chen et. al. yields probability sums > 1.0, fixing it by reducing flow probability");
+        warn!("Ton: {:?}", ton);
+        warn!("Surfel: {:?}", surfel);
+        ton.p_flow -= ton.p_straight + ton.p_parabolic + ton.p_flow - 1.0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use config::{Sides, Transport};
+    use geom::{Aabb, Vec2};
+    use geometry::Intersection;
+    use std::f32::INFINITY;
+    use std::sync::Arc;
+    use stratify::EmissionSampling;
+    use surf::{SurfaceBuilder, SurfelSampling};
+    use test_fixtures::{quad, x_z_quad};
+    use ton::{FlowField, TonSourceBuilder};
+
+    #[test]
+    fn test_evaporation_decays_exponentially() {
+        let mut ton = TonSourceBuilder::new()
+            .substances(&vec![1.0, 1.0])
+            .pickup_rates(vec![0.0, 0.0])
+            .evaporation_rates(vec![0.5, 0.0])
+            .build()
+            .emit_one()
+            .ton;
+
+        travel(&mut ton, 2.0);
+        assert_relative_eq!(ton.path_length, 2.0);
+        assert_relative_eq!(ton.substances[0], (-1.0_f32).exp(), epsilon = 0.0001);
+        assert_relative_eq!(ton.substances[1], 1.0);
+
+        // Splitting the path into segments evaporates the same amount
+        travel(&mut ton, 0.5);
+        travel(&mut ton, 1.5);
+        assert_relative_eq!(ton.path_length, 4.0);
+        assert_relative_eq!(ton.substances[0], (-2.0_f32).exp(), epsilon = 0.0001);
+    }
+
+    #[test]
+    fn test_settles_after_max_path_length() {
+        let mut ton = TonSourceBuilder::new()
+            .p_straight(1.0)
+            .max_path_length(1.0)
+            .build()
+            .emit_one()
+            .ton;
+
+        travel(&mut ton, 0.9);
+        assert_eq!(select_motion_type(&ton), MotionType::Straight);
+
+        travel(&mut ton, 0.2);
+        assert_eq!(select_motion_type(&ton), MotionType::Settled);
+    }
+
+    #[test]
+    fn test_flow_direction_gravity() {
+        let ton = TonSourceBuilder::new()
+            .flow_direction_gravity()
+            .build()
+            .emit_one()
+            .ton;
+
+        // Runs downhill on the tilted quad
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let downhill = Vec3::new(0.0, -1.0, 2.0).normalize();
+        let flow = flow_direction_on(&tilted_quad(), &ton, Vec3::new(0.0, 0.5, 0.0), down);
+        assert_relative_eq!(flow, downhill, epsilon = 0.0001);
+
+        // Gravity is perpendicular to the flat quad, flows in the incoming direction instead
+        let incoming = Vec3::new(1.0, -1.0, 0.0);
+        let flow = flow_direction_on(&x_z_quad(), &ton, Vec3::new(0.0, 0.0, 0.0), incoming);
+        assert_relative_eq!(flow, Vec3::new(1.0, 0.0, 0.0), epsilon = 0.0001);
+
+        // Falls back to the tangent of the triangle if the incoming direction is
+        // perpendicular too
+        let flat = x_z_quad();
+        let flow = flow_direction_on(&flat, &ton, Vec3::new(0.0, 0.0, 0.0), down);
+        assert_relative_eq!(flow, flat[0].tangent(), epsilon = 0.0001);
+    }
+
+    #[test]
+    fn test_flow_direction_field() {
+        // Linear, so interpolating the vertex field yields the same as the procedural one
+        let field = |p: Vec3| Vec3::new(p.z, 0.0, -p.x);
+        let fields = vec![
+            FlowField::Vertex(Arc::new(move |v: &Vertex| field(v.position))),
+            FlowField::Procedural(Arc::new(move |p| field(p))),
+        ];
+
+        for flow_field in fields {
+            let ton = TonSourceBuilder::new()
+                .flow_direction_field(flow_field)
+                .build()
+                .emit_one()
+                .ton;
+
+            // Tangential field on the flat quad is followed as is
+            let down = Vec3::new(0.0, -1.0, 0.0);
+            let point = Vec3::new(0.5, 0.0, 0.25);
+            let flow = flow_direction_on(&x_z_quad(), &ton, point, down);
+            assert_relative_eq!(flow, field(point).normalize(), epsilon = 0.0001);
+
+            // Projected onto the tilted quad
+            let point = Vec3::new(0.5, 0.5, 0.0);
+            let normal = tilted_quad()[0].normal();
+            let projected = field(point) - normal * normal.dot(field(point));
+            let flow = flow_direction_on(&tilted_quad(), &ton, point, down);
+            assert_relative_eq!(flow, projected.normalize(), epsilon = 0.0001);
+
+            // Fields vanishing at the hit point fall back to the incoming direction, then
+            // to the tangent
+            let origin = Vec3::new(0.0, 0.0, 0.0);
+            let incoming = Vec3::new(0.0, -1.0, -1.0);
+            let flow = flow_direction_on(&x_z_quad(), &ton, origin, incoming);
+            assert_relative_eq!(flow, Vec3::new(0.0, 0.0, -1.0), epsilon = 0.0001);
+            let flat = x_z_quad();
+            let flow = flow_direction_on(&flat, &ton, origin, down);
+            assert_relative_eq!(flow, flat[0].tangent(), epsilon = 0.0001);
+        }
+    }
+
+    /// Flow direction of a ton hitting the first triangle of the given triangles at the
+    /// given point.
+    fn flow_direction_on(triangles: &[Tri], ton: &Ton, point: Vec3, incoming: Vec3) -> Vec3 {
+        let tracer = Tracer::new(triangles.iter().cloned());
+        let triangle = &triangles[0];
+        let normal = triangle.normal();
+        let (up, flow) = Simulation::flow_direction(
+            &tracer,
+            ton,
+            point,
+            incoming,
+            Some(triangle),
+            normal,
+            normal,
+        );
+        assert_relative_eq!(up, normal);
+        assert_relative_eq!(flow.dot(normal), 0.0, epsilon = 0.0001);
+        flow
+    }
+
+    /// Two by two quad rising towards negative Z, facing up and towards positive Z.
+    fn tilted_quad() -> Vec<Tri> {
+        quad(
+            Vec3::new(-1.0, 0.0, 1.0),
+            Vec3::new(1.0, 0.0, 1.0),
+            Vec3::new(1.0, 1.0, -1.0),
+            Vec3::new(-1.0, 1.0, -1.0),
+        )
+    }
+
+    #[test]
+    fn test_hangs_at_overhang_then_drips() {
+        // Shelf on the X/Z plane overhanging a floor, the ton flows off its edge at X = 1
+        let shelf = x_z_quad();
+        let floor = quad(
+            Vec3::new(-1.0, -2.0, 1.0),
+            Vec3::new(3.0, -2.0, 1.0),
+            Vec3::new(3.0, -2.0, -1.0),
+            Vec3::new(-1.0, -2.0, -1.0),
+        );
+        let surface = surface(&shelf, &surfel_data(vec![0.0], vec![0.0]));
+        let hang_steps = 3;
+
+        let ton = TonSourceBuilder::new()
+            .p_straight(0.0)
+            .p_parabolic(0.0)
+            .p_flow(1.0)
+            .flow_direction_static(Vec3::new(1.0, 0.0, 0.0))
+            .flow_distance(0.5)
+            .substances(&vec![0.0])
+            .pickup_rates(vec![0.0])
+            .build()
+            .emit_one()
+            .ton;
+        let config = Config {
+            dripping: Some(Dripping { hang_steps }),
+            ..Default::default()
+        };
+        let triangles = shelf.iter().cloned().chain(floor);
+        let mut sim = Simulation::new_with_config(config, vec![], triangles, surface, vec![]);
+
+        let mut hits = {
+            let down = Vec3::new(0.0, -1.0, 0.0);
+            let hit = sim.tracer.trace_straight(Vec3::new(0.9, 1.0, 0.0), down);
+            vec![contact(ton, hit.unwrap(), 0.0)]
+        };
+        let edge = hits[0].intersection_point;
+
+        // Hangs on to the shelf where it was, interacting with it again
+        for hanging_steps in 1..(hang_steps + 1) {
+            hits = sim.trace_deepen(hits);
+            assert_eq!(hits.len(), 1);
+            assert_eq!(hits[0].ton.hanging_steps, hanging_steps);
+            assert_relative_eq!(hits[0].intersection_point, edge);
+            assert!(sim.drip_counts().iter().all(|&count| count == 0));
+        }
+
+        // Then drips onto the floor, counted at the surfel nearest to where it was hanging
+        hits = sim.trace_deepen(hits);
+        assert_eq!(hits.len(), 1);
+        assert_eq!(hits[0].ton.hanging_steps, 0);
+        assert_relative_eq!(hits[0].intersection_point.y, -2.0, epsilon = 0.0001);
+
+        let nearest_idx = sim.surface().nearest_idx(edge);
+        for (idx, &count) in sim.drip_counts().iter().enumerate() {
+            assert_eq!(count, if idx == nearest_idx { 1 } else { 0 });
+        }
+    }
+
+    #[test]
+    fn test_splash_children() {
+        let splashing = Splashing {
+            children: 4,
+            min_speed: None,
+            min_incidence_cos: Some(0.5),
+            cone_angle: 0.3,
+            max_generations: 2,
+        };
+        let tracer = Tracer::new(x_z_quad().into_iter().chain(ceiling(2.0)));
+        let ton = TonSourceBuilder::new()
+            .substances(&vec![1.0, 0.5])
+            .pickup_rates(vec![0.0, 0.0])
+            .build()
+            .emit_one()
+            .ton;
+        let carried = |tons: &[&Ton], substance_idx: usize| {
+            tons.iter()
+                .map(|t| t.weight * t.substances[substance_idx])
+                .sum::<f32>()
+        };
+
+        let config = Config::default();
+        let surface_normal = Simulation::surface_normal;
+        let normal = |hit: &Contact| surface_normal(&tracer, &config, hit);
+
+        let (from, down) = (Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, -1.0, 0.0));
+        let hit = tracer.trace_straight(from, down).unwrap();
+        let parent = contact(ton, hit, 0.0);
+        assert!(splashes(&splashing, &parent));
+        let parent_path_length = parent.ton.path_length;
+        let parent_carried = (carried(&[&parent.ton], 0), carried(&[&parent.ton], 1));
+
+        // Children fly up to the ceiling, conserving the carried substance
+        let parent_normal = normal(&parent);
+        let children = Simulation::splash(
+            &tracer,
+            &splashing,
+            parent,
+            parent_normal,
+            MotionType::Straight,
+        );
+        assert_eq!(children.len(), splashing.children);
+        let child_tons: Vec<&Ton> = children.iter().map(|c| &c.ton).collect();
+        assert_relative_eq!(carried(&child_tons, 0), parent_carried.0, epsilon = 0.0001);
+        assert_relative_eq!(carried(&child_tons, 1), parent_carried.1, epsilon = 0.0001);
+        for child in children.iter() {
+            assert_eq!(child.ton.splash_generation, 1);
+            assert_relative_eq!(child.intersection_point.y, 2.0, epsilon = 0.0001);
+            // Children continue the path of their parent
+            assert!(child.ton.path_length >= parent_path_length + 2.0 - 0.0001);
+        }
+
+        // Children splash again, grandchildren reached the maximum generations
+        let child = children.into_iter().next().unwrap();
+        assert!(splashes(&splashing, &child));
+        let child_normal = normal(&child);
+        let grandchildren = Simulation::splash(
+            &tracer,
+            &splashing,
+            child,
+            child_normal,
+            MotionType::Straight,
+        );
+        assert_eq!(grandchildren.len(), splashing.children);
+        for grandchild in grandchildren.iter() {
+            assert_eq!(grandchild.ton.splash_generation, 2);
+            assert!(!splashes(&splashing, grandchild));
+        }
+    }
+
+    #[test]
+    fn test_splash_children_count_against_limits() {
+        // Tons bounce between floor and ceiling and splash on every impact
+        let splashing = Splashing {
+            children: 3,
+            min_speed: None,
+            min_incidence_cos: Some(0.0),
+            cone_angle: 0.3,
+            max_generations: 2,
+        };
+        let floor = quad(
+            Vec3::new(-10.0, 0.0, 10.0),
+            Vec3::new(10.0, 0.0, 10.0),
+            Vec3::new(10.0, 0.0, -10.0),
+            Vec3::new(-10.0, 0.0, -10.0),
+        );
+        let emission_count = 10;
+        let source = TonSourceBuilder::new()
+            .p_straight(1.0)
+            .p_parabolic(0.0)
+            .p_flow(0.0)
+            .max_path_length(7.0)
+            .substances(&vec![0.0])
+            .pickup_rates(vec![0.0])
+            .build();
+        let config = Config {
+            splashing: Some(splashing),
+            ..Default::default()
+        };
+        let surface = surface(&floor, &surfel_data(vec![0.0], vec![0.0]));
+        let triangles = floor.iter().cloned().chain(ceiling(2.0));
+        let mut sim = Simulation::new_with_config(config, vec![], triangles, surface, vec![]);
+
+        // All tons fall straight down onto the floor
+        let mut hits: Vec<Contact> = (0..emission_count)
+            .map(|_| {
+                let down = Vec3::new(0.0, -1.0, 0.0);
+                let hit = sim.tracer.trace_straight(Vec3::new(0.0, 1.0, 0.0), down);
+                contact(source.emit_one().ton, hit.unwrap(), 0.0)
+            })
+            .collect();
+
+        // Narrow cones keep all children between floor and ceiling, so every ton splashes
+        // into exactly three children until the maximum generation is reached
+        hits = sim.trace_deepen(hits);
+        assert_eq!(hits.len(), emission_count * 3);
+        assert!(hits.iter().all(|h| h.ton.splash_generation == 1));
+        hits = sim.trace_deepen(hits);
+        assert_eq!(hits.len(), emission_count * 3 * 3);
+        assert!(hits.iter().all(|h| h.ton.splash_generation == 2));
+
+        // Every bounce travels at least the distance between floor and ceiling, children
+        // continue the path length of their parents and settle with them
+        let mut iterations = 2;
+        while !hits.is_empty() {
+            hits = sim.trace_deepen(hits);
+            iterations += 1;
+            assert!(hits.len() <= emission_count * 3 * 3);
+            assert!(iterations <= 5);
+        }
+    }
+
+    /// Twenty by twenty quad parallel to the X/Z plane at the given height, facing down.
+    fn ceiling(height: f32) -> Vec<Tri> {
+        quad(
+            Vec3::new(-10.0, height, -10.0),
+            Vec3::new(10.0, height, -10.0),
+            Vec3::new(10.0, height, 10.0),
+            Vec3::new(-10.0, height, 10.0),
+        )
+    }
+
+    #[test]
+    fn test_interpolated_normals() {
+        let smooth = Config {
+            smooth_normals: true,
+            ..Default::default()
+        };
+        let flat = Config::default();
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let normal = Simulation::surface_normal;
+
+        // Interpolated at the centroid, on both sides
+        let normals = [
+            Vec3::new(1.0, 1.0, 0.0).normalize(),
+            Vec3::new(0.0, 1.0, 1.0).normalize(),
+            up,
+        ];
+        let expected = (normals[0] + normals[1] + normals[2]).normalize();
+        let (tracer, front, back) = centroid_contacts(normals);
+        assert_relative_eq!(normal(&tracer, &smooth, &front), expected, epsilon = 0.0001);
+        assert_relative_eq!(normal(&tracer, &smooth, &back), -expected, epsilon = 0.0001);
+        assert_relative_eq!(normal(&tracer, &flat, &front), up, epsilon = 0.0001);
+        assert_relative_eq!(normal(&tracer, &flat, &back), -up, epsilon = 0.0001);
+
+        // Vertex normals facing the other side of the triangle fall back to the face normal
+        let (tracer, front, back) = centroid_contacts([-up, -up, -up]);
+        assert_relative_eq!(normal(&tracer, &smooth, &front), up, epsilon = 0.0001);
+        assert_relative_eq!(normal(&tracer, &smooth, &back), -up, epsilon = 0.0001);
+
+        // So do vertex normals cancelling out at the hit point
+        let x = Vec3::new(1.0, 0.0, 0.0);
+        let (tracer, front, _) = centroid_contacts([x, -x, Vec3::new(0.0, 0.0, 0.0)]);
+        assert_relative_eq!(normal(&tracer, &smooth, &front), up, epsilon = 0.0001);
+    }
+
+    #[test]
+    fn test_keep_off_surface() {
+        let face_normal = Vec3::new(0.0, 1.0, 0.0);
+        let tilted = Vec3::new(1.0, 1.0, 0.0).normalize();
+
+        // Directions around the tilted normal reaching into the surface are mirrored back out
+        let reflected = keep_off_surface(face_normal, tilted, Vec3::new(1.0, -0.2, 0.0));
+        assert_relative_eq!(reflected, Vec3::new(1.0, 0.2, 0.0), epsilon = 0.0001);
+        let above = Vec3::new(0.0, 1.0, 0.0);
+        assert_relative_eq!(keep_off_surface(face_normal, tilted, above), above);
+
+        // Also on the back side, for normals flipped to the side that was hit
+        let reflected = keep_off_surface(face_normal, -tilted, Vec3::new(-1.0, 0.2, 0.0));
+        assert_relative_eq!(reflected, Vec3::new(-1.0, -0.2, 0.0), epsilon = 0.0001);
+    }
+
+    /// Traces a triangle on the X/Z plane facing up with the given vertex normals from
+    /// above and below, returning the tracer and the contacts at the centroid.
+    fn centroid_contacts(normals: [Vec3; 3]) -> (Tracer, Contact, Contact) {
+        let vertex = |position, normal| Vertex {
+            position,
+            normal,
+            texcoords: Vec2::new(0.0, 0.0),
+        };
+        let triangle = TupleTriangle(
+            vertex(Vec3::new(-1.0, 0.0, 1.0), normals[0]),
+            vertex(Vec3::new(1.0, 0.0, 1.0), normals[1]),
+            vertex(Vec3::new(1.0, 0.0, -1.0), normals[2]),
+        );
+        let tracer = Tracer::new(vec![triangle]);
+        let centroid = Vec3::new(1.0 / 3.0, 0.0, 1.0 / 3.0);
+
+        let contact_from = |height: f32| {
+            let ton = TonSourceBuilder::new().build().emit_one().ton;
+            let from = centroid + Vec3::new(0.0, height, 0.0);
+            let hit = tracer.trace_straight(from, -from + centroid).unwrap();
+            contact(ton, hit, 0.0)
+        };
+        let front = contact_from(1.0);
+        let back = contact_from(-1.0);
+        assert!(front.front_face && !back.front_face);
+
+        (tracer, front, back)
+    }
+
+    #[test]
+    fn test_back_faces_pass_through() {
+        let (upper, lower) = deposited_behind_ceiling(BackFace::PassThrough);
+        assert_relative_eq!(upper, 0.0);
+        assert!(lower > 0.0);
+    }
+
+    #[test]
+    fn test_back_faces_absorb() {
+        let (upper, lower) = deposited_behind_ceiling(BackFace::Absorb);
+        assert!(upper > 0.0);
+        assert_relative_eq!(lower, 0.0);
+    }
+
+    /// Shoots tons down through a single-sided ceiling facing away from the source onto
+    /// the quad below and returns what was deposited on the ceiling and on the floor.
+    fn deposited_behind_ceiling(back_face: BackFace) -> (f32, f32) {
+        let ceiling = quad(
+            Vec3::new(-1.0, 1.0, -1.0),
+            Vec3::new(1.0, 1.0, -1.0),
+            Vec3::new(1.0, 1.0, 1.0),
+            Vec3::new(-1.0, 1.0, 1.0),
+        );
+        let floor = x_z_quad();
+
+        let ceiling_surfel = surfel_data(vec![0.0], vec![1.0]);
+        let floor_surfel = SurfelData {
+            entity_idx: 1,
+            ..ceiling_surfel.clone()
+        };
+        let surface = SurfaceBuilder::new()
+            .sampling(SurfelSampling::MinimumDistance(0.05))
+            .sample_triangles(ceiling.iter().cloned(), &ceiling_surfel)
+            .sample_triangles(floor.iter().cloned(), &floor_surfel)
+            .build();
+
+        // Every ton reaching the floor has to cross the ceiling
+        let source = TonSourceBuilder::new()
+            .point_shaped(0.0, 3.0, 0.0)
+            .emission_count(1000)
+            .p_straight(0.0)
+            .p_parabolic(0.0)
+            .p_flow(0.0)
+            .substances(&vec![1.0])
+            .pickup_rates(vec![0.0])
+            .build();
+        let config = Config {
+            transport: Transport::consistent(),
+            sides: Sides {
+                default: Sidedness::TwoSided,
+                entities: vec![Some(Sidedness::SingleSided(back_face)), None],
+            },
+            ..Default::default()
+        };
+        let triangles = ceiling.into_iter().chain(floor);
+
+        let mut sim = Simulation::new_with_config(config, vec![source], triangles, surface, vec![]);
+        sim.run();
+
+        let deposited = |entity_idx| {
+            sim.surface()
+                .samples
+                .iter()
+                .map(|s| s.data())
+                .filter(|d| d.entity_idx == entity_idx)
+                .map(|d| d.substances[0])
+                .sum::<f32>()
+        };
+        (deposited(0), deposited(1))
+    }
+
+    #[test]
+    fn test_aiming_keeps_transport_unbiased() {
+        // Tons settle on the quad below, depositing the first substance and picking up
+        // a little of the second
+        let transported = |aim: bool| {
+            let source = TonSourceBuilder::new()
+                .point_shaped(0.0, 3.0, 0.0)
+                .emission_count(20000)
+                .emission_sampling(EmissionSampling::Stratified)
+                .substances(&vec![1.0, 0.0])
+                .pickup_rates(vec![0.0, 0.01]);
+            let source = if aim {
+                source.aim_at_region(Vec3::new(0.0, 0.0, 0.0), 2.0_f32.sqrt())
+            } else {
+                source
+            };
+
+            let config = Config {
+                transport: Transport::consistent(),
+                ..Default::default()
+            };
+            let surface = surface(&x_z_quad(), &surfel_data(vec![0.0, 1.0], vec![0.5, 0.0]));
+            let sources = vec![source.build()];
+            let mut sim = Simulation::new_with_config(config, sources, x_z_quad(), surface, vec![]);
+            sim.run();
+
+            let samples = &sim.surface().samples;
+            let deposited: f32 = samples.iter().map(|s| s.data().substances[0]).sum();
+            let picked_up: f32 = samples.iter().map(|s| 1.0 - s.data().substances[1]).sum();
+            (deposited, picked_up)
+        };
+
+        let (deposited, picked_up) = transported(false);
+        let (aimed_deposited, aimed_picked_up) = transported(true);
+
+        assert!(deposited > 0.0 && picked_up > 0.0);
+        assert_relative_eq!(aimed_deposited, deposited, max_relative = 0.1);
+        assert_relative_eq!(aimed_picked_up, picked_up, max_relative = 0.1);
+    }
+
+    #[test]
+    fn test_wind_bias_cancelling_direction() {
+        let down = Vec3::new(0.0, -1.0, 0.0);
+        let sideways = Vec3::new(1.0, 0.0, 0.0);
+
+        assert_eq!(wind_biased_direction(down, 0.5, -2.0 * down), down);
+        assert_relative_eq!(
+            wind_biased_direction(down, 1.0, sideways),
+            Vec3::new(1.0, -1.0, 0.0).normalize()
+        );
+    }
+
+    #[test]
+    fn test_keeps_sources_fitted_in_builder() {
+        // Fitted to bounds far away from the quad of the scene
+        let source = TonSourceBuilder::new()
+            .directional_shaped(Vec3::new(0.0, -1.0, 0.0))
+            .fit_to_bounds(&Aabb {
+                min: Vec3::new(99.0, -1.0, -1.0),
+                max: Vec3::new(101.0, 1.0, 1.0),
+            })
+            .build();
+
+        let surface = surface(&x_z_quad(), &surfel_data(vec![0.0], vec![0.0]));
+        let sim = Simulation::new(vec![source], x_z_quad(), surface, vec![]);
+
+        let emission = sim.sources[0].emit_one();
+        assert!(emission.weight > 0.0);
+        assert!(emission.origin.x > 90.0);
+    }
+
+    #[test]
+    fn test_nearest_surfel_of_hit_entity() {
+        // Two quads side by side, the left one only has surfels far from the boundary
+        let left = quad(
+            Vec3::new(-3.0, 0.0, 1.0),
+            Vec3::new(-1.0, 0.0, 1.0),
+            Vec3::new(-1.0, 0.0, -1.0),
+            Vec3::new(-3.0, 0.0, -1.0),
+        );
+        let far_left = quad(
+            Vec3::new(-3.0, 0.0, 1.0),
+            Vec3::new(-2.5, 0.0, 1.0),
+            Vec3::new(-2.5, 0.0, -1.0),
+            Vec3::new(-3.0, 0.0, -1.0),
+        );
+        let left_surfel = surfel_data(vec![0.0], vec![1.0]);
+        let right_surfel = SurfelData {
+            entity_idx: 1,
+            ..left_surfel.clone()
+        };
+        let surface = SurfaceBuilder::new()
+            .sampling(SurfelSampling::MinimumDistance(0.05))
+            .sample_triangles(far_left.iter().cloned(), &left_surfel)
+            .sample_triangles(x_z_quad().iter().cloned(), &right_surfel)
+            .build();
+        let entity_surfels = entity_surfels(&surface);
+
+        let point = Vec3::new(-1.05, 0.0, 0.0);
+        let nearest = nearest_of_entity(&surface, 0, &entity_surfels[0], point, 0.1);
+        let expected = nearest_of(&surface, entity_surfels[0].iter().cloned(), point);
+        assert_eq!(Some(nearest), expected);
+        assert_eq!(surface.samples[nearest].data().entity_idx, 0);
+        assert!(surface.samples[nearest].vertex().position.x <= -2.5 + 0.0001);
+
+        // Tons hitting the left quad near the boundary only deposit on the left quad
+        let source = TonSourceBuilder::new()
+            .point_shaped(-1.05, 1.0, 0.0)
+            .emission_count(100)
+            .aim_at_region(point, 0.01)
+            .p_straight(0.0)
+            .p_parabolic(0.0)
+            .p_flow(0.0)
+            .substances(&vec![1.0])
+            .pickup_rates(vec![0.0])
+            .build();
+        let config = Config {
+            transport: Transport::consistent(),
+            restrict_to_hit_entity: true,
+            ..Default::default()
+        };
+        let triangles = left
+            .into_iter()
+            .map(|t| (0, t))
+            .chain(x_z_quad().into_iter().map(|t| (1, t)));
+        let mut sim =
+            Simulation::new_with_entities(config, vec![source], triangles, surface, vec![]);
+        sim.run();
+
+        for surfel in sim.surface().samples.iter() {
+            if surfel.data().entity_idx == 1 {
+                assert_relative_eq!(surfel.data().substances[0], 0.0);
             }
-            MotionType::Settled => None,
         }
     }
 
-    fn select_motion_type(ton: &Ton) -> MotionType {
-        let mut rng = rand::thread_rng();
-        let random: f32 = rng.gen();
+    #[test]
+    fn test_restricted_hit_entity_without_surfels() {
+        // Two quads side by side, only the right one has surfels
+        let left = quad(
+            Vec3::new(-3.0, 0.0, 1.0),
+            Vec3::new(-1.0, 0.0, 1.0),
+            Vec3::new(-1.0, 0.0, -1.0),
+            Vec3::new(-3.0, 0.0, -1.0),
+        );
+        let right_surfel = SurfelData {
+            entity_idx: 1,
+            ..surfel_data(vec![0.0], vec![1.0])
+        };
+        let surface = surface(&x_z_quad(), &right_surfel);
 
-        let &Ton {
-            p_straight,
-            p_parabolic,
-            p_flow,
-            ..
-        } = ton;
-
-        if random < p_straight {
-            MotionType::Straight
-        } else if random < (p_straight + p_parabolic) {
-            MotionType::Parabolic
-        } else if random < (p_straight + p_parabolic + p_flow) {
-            MotionType::Flow
-        } else {
-            MotionType::Settled
+        // Tons hitting the left quad near the boundary do not deposit anywhere
+        let source = TonSourceBuilder::new()
+            .point_shaped(-1.05, 1.0, 0.0)
+            .emission_count(100)
+            .aim_at_region(Vec3::new(-1.05, 0.0, 0.0), 0.01)
+            .p_straight(0.0)
+            .p_parabolic(0.0)
+            .p_flow(0.0)
+            .substances(&vec![1.0])
+            .pickup_rates(vec![0.0])
+            .build();
+        let config = Config {
+            transport: Transport::consistent(),
+            restrict_to_hit_entity: true,
+            ..Default::default()
+        };
+        let triangles = left
+            .into_iter()
+            .map(|t| (0, t))
+            .chain(x_z_quad().into_iter().map(|t| (1, t)));
+        let mut sim =
+            Simulation::new_with_entities(config, vec![source], triangles, surface, vec![]);
+        sim.run();
+
+        for surfel in sim.surface().samples.iter() {
+            assert_relative_eq!(surfel.data().substances[0], 0.0);
         }
     }
 
-    fn deteriorate(ton: &mut Ton, surfel: &SurfelData) {
-        ton.p_straight = (ton.p_straight - surfel.delta_straight).max(0.0);
-        ton.p_parabolic = (ton.p_parabolic - surfel.delta_parabolic).max(0.0);
-        ton.p_flow = (ton.p_flow + ton.p_parabolic - surfel.delta_flow).max(0.0);
+    #[test]
+    fn test_geometry_without_triangles() {
+        // Two by two square on the X/Z plane, intersected analytically
+        struct Square;
+
+        impl Square {
+            fn intersect(&self, origin: Vec3, direction: Vec3, max_t: f32) -> Option<Intersection> {
+                if direction.y == 0.0 {
+                    return None;
+                }
 
-        // REVIEW why this equation for flow? why doesn't it deteriorate like the others
-        //        the sum could be larger than 1.0
-        //
-        // Alternative (sane?) version:
-        // ton.p_flow = (ton.p_flow - surfel.delta_flow).max(0.0);
+                let t = -origin.y / direction.y;
+                let point = origin + t * direction;
+                if t >= 0.0 && t <= max_t && point.x.abs() <= 1.0 && point.z.abs() <= 1.0 {
+                    Some(Intersection {
+                        idx: 0,
+                        t,
+                        normal: Vec3::new(0.0, 1.0, 0.0),
+                        barycentric: None,
+                    })
+                } else {
+                    None
+                }
+            }
+        }
+
+        impl Geometry for Square {
+            fn ray_intersection(&self, origin: Vec3, direction: Vec3) -> Option<Intersection> {
+                self.intersect(origin, direction, INFINITY)
+            }
+
+            fn line_segment_intersection(
+                &self,
+                origin: Vec3,
+                direction: Vec3,
+                length: f32,
+            ) -> Option<Intersection> {
+                self.intersect(origin, direction, length)
+            }
+
+            fn bounds(&self) -> Aabb {
+                Aabb {
+                    min: Vec3::new(-1.0, 0.0, -1.0),
+                    max: Vec3::new(1.0, 0.0, 1.0),
+                }
+            }
+        }
+
+        let source = TonSourceBuilder::new()
+            .point_shaped(0.0, 3.0, 0.0)
+            .emission_count(1000)
+            .aim_at_region(Vec3::new(0.0, 0.0, 0.0), 2.0_f32.sqrt())
+            .p_straight(0.3)
+            .p_parabolic(0.3)
+            .p_flow(0.3)
+            .flow_direction_gravity()
+            .substances(&vec![1.0])
+            .pickup_rates(vec![0.0])
+            .build();
+        // Smooth normals and flow fall back to the reported normal without triangles
+        let config = Config {
+            smooth_normals: true,
+            transport: Transport::consistent(),
+            ..Default::default()
+        };
+        let surface = surface(&x_z_quad(), &surfel_data(vec![0.0], vec![0.5]));
+
+        let mut sim = Simulation::new_with_geometry(config, vec![source], Square, surface, vec![]);
+        sim.run();
+
+        let samples = &sim.surface().samples;
+        let deposited: f32 = samples.iter().map(|s| s.data().substances[0]).sum();
+        assert!(deposited > 0.0);
+        assert!(deposited <= 1000.0 + 0.001);
+        assert!(sim.missed_emission_fraction() < 0.5);
+    }
+
+    /// Samples surfels with the given prototype on the given triangles.
+    fn surface(triangles: &[Tri], prototype: &SurfelData) -> Surface {
+        SurfaceBuilder::new()
+            .sampling(SurfelSampling::MinimumDistance(0.05))
+            .sample_triangles(triangles.iter().cloned(), prototype)
+            .build()
+    }
 
-        if (ton.p_straight + ton.p_parabolic + ton.p_flow) > 1.0 {
-            warn!("WARN: The flow equation from Chen et. al. yields probability sums > 1.0, fixing it by reducing flow probability");
-            warn!("Ton: {:?}", ton);
-            warn!("Surfel: {:?}", surfel);
-            ton.p_flow -= ton.p_straight + ton.p_parabolic + ton.p_flow - 1.0
+    fn surfel_data(substances: Vec<f32>, deposition_rates: Vec<f32>) -> SurfelData {
+        SurfelData {
+            entity_idx: 0,
+            delta_straight: 0.0,
+            delta_parabolic: 0.0,
+            delta_flow: 0.0,
+            substances,
+            deposition_rates,
+            rules: vec![],
         }
     }
 }
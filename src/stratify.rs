@@ -0,0 +1,258 @@
+use rand::{self, Rng};
+
+/// Maximum number of two-dimensional samples drawn for a single emission, e.g. one for
+/// the position and one for the direction.
+const SAMPLE_PAIRS: usize = 2;
+/// Maximum number of one-dimensional samples drawn for a single emission, e.g. to select
+/// a triangle of a mesh.
+const SAMPLE_SCALARS: usize = 1;
+/// Halton bases for each dimension of each sample pair.
+const HALTON_BASES: [[usize; 2]; SAMPLE_PAIRS] = [[2, 3], [5, 7]];
+/// Halton bases for each scalar sample.
+const HALTON_SCALAR_BASES: [usize; SAMPLE_SCALARS] = [11];
+/// Strides to permute strata between sample pairs, so positions and directions
+/// are not correlated. Primes, so they are coprime with most emission counts.
+const STRATUM_STRIDES: [usize; SAMPLE_PAIRS] = [1, 1_000_003];
+/// Strides to permute strata of scalar samples, different from the ones of the pairs.
+const SCALAR_STRATUM_STRIDES: [usize; SAMPLE_SCALARS] = [999_983];
+
+/// Strategy to distribute the samples that emission positions and directions are
+/// derived from.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EmissionSampling {
+    /// Independent uniformly distributed random samples.
+    Random,
+    /// Centers of the cells of a regular grid with one cell per emission,
+    /// the same in every iteration. Cells of an incomplete last row are
+    /// stretched to cover the full width.
+    Stratified,
+    /// One uniformly distributed random sample in each cell of a regular grid
+    /// with one cell per emission.
+    Jittered,
+    /// Halton sequence, randomly shifted in every iteration.
+    Halton,
+}
+
+impl Default for EmissionSampling {
+    fn default() -> Self {
+        EmissionSampling::Random
+    }
+}
+
+/// Provides the samples for one batch of emissions of a source, e.g. the emissions
+/// of one iteration.
+pub struct EmissionSampler {
+    sampling: EmissionSampling,
+    count: usize,
+    /// Random offsets of stratum indexes for each sample pair
+    stratum_offsets: [usize; SAMPLE_PAIRS],
+    /// Random offsets of stratum indexes for each scalar sample
+    scalar_stratum_offsets: [usize; SAMPLE_SCALARS],
+    /// Random toroidal shift of each dimension of the Halton sequence
+    halton_shifts: [[f32; 2]; SAMPLE_PAIRS],
+    /// Random toroidal shift of the Halton sequence of each scalar sample
+    scalar_halton_shifts: [f32; SAMPLE_SCALARS],
+}
+
+impl EmissionSampler {
+    /// Prepares sampling for a batch of the given amount of emissions.
+    pub fn new(sampling: EmissionSampling, count: usize) -> Self {
+        let mut rng = rand::thread_rng();
+        let mut stratum_offsets = [0; SAMPLE_PAIRS];
+        let mut scalar_stratum_offsets = [0; SAMPLE_SCALARS];
+        let mut halton_shifts = [[0.0; 2]; SAMPLE_PAIRS];
+        let mut scalar_halton_shifts = [0.0; SAMPLE_SCALARS];
+
+        for pair in 0..SAMPLE_PAIRS {
+            stratum_offsets[pair] = rng.gen_range(0, count.max(1));
+            halton_shifts[pair] = [rng.gen(), rng.gen()];
+        }
+
+        for dim in 0..SAMPLE_SCALARS {
+            scalar_stratum_offsets[dim] = rng.gen_range(0, count.max(1));
+            scalar_halton_shifts[dim] = rng.gen();
+        }
+
+        EmissionSampler {
+            sampling,
+            count,
+            stratum_offsets,
+            scalar_stratum_offsets,
+            halton_shifts,
+            scalar_halton_shifts,
+        }
+    }
+
+    /// Gets two numbers in `[0, 1)` for the emission with the given index, where `pair`
+    /// selects independent samples for different purposes, e.g. `0` for the position
+    /// and `1` for the direction.
+    pub fn sample(&self, emission_idx: usize, pair: usize) -> (f32, f32) {
+        assert!(pair < SAMPLE_PAIRS, "Sample pair out of range");
+
+        match self.sampling {
+            EmissionSampling::Random => {
+                let mut rng = rand::thread_rng();
+                (rng.gen(), rng.gen())
+            }
+            EmissionSampling::Stratified => self.stratum(emission_idx, pair, (0.5, 0.5)),
+            EmissionSampling::Jittered => {
+                let mut rng = rand::thread_rng();
+                self.stratum(emission_idx, pair, (rng.gen(), rng.gen()))
+            }
+            EmissionSampling::Halton => {
+                let bases = HALTON_BASES[pair];
+                let shifts = self.halton_shifts[pair];
+                (
+                    wrap(radical_inverse(emission_idx + 1, bases[0]) + shifts[0]),
+                    wrap(radical_inverse(emission_idx + 1, bases[1]) + shifts[1]),
+                )
+            }
+        }
+    }
+
+    /// Gets a number in `[0, 1)` for the emission with the given index, where `dim`
+    /// selects independent samples for different purposes, e.g. `0` to select a triangle
+    /// of a mesh.
+    ///
+    /// Unlike a coordinate of a pair, which takes only about as many distinct values as
+    /// the square root of the emission count when stratified, every emission of the batch
+    /// gets its own stratum.
+    pub fn sample_1d(&self, emission_idx: usize, dim: usize) -> f32 {
+        assert!(dim < SAMPLE_SCALARS, "Scalar sample dimension out of range");
+
+        match self.sampling {
+            EmissionSampling::Random => rand::thread_rng().gen(),
+            EmissionSampling::Stratified => self.stratum_1d(emission_idx, dim, 0.5),
+            EmissionSampling::Jittered => {
+                let offset = rand::thread_rng().gen();
+                self.stratum_1d(emission_idx, dim, offset)
+            }
+            EmissionSampling::Halton => wrap(
+                radical_inverse(emission_idx + 1, HALTON_SCALAR_BASES[dim])
+                    + self.scalar_halton_shifts[dim],
+            ),
+        }
+    }
+
+    /// Places a number at the given offset inside the interval of the N equally sized
+    /// intervals of `[0, 1)` assigned to the emission with the given index.
+    fn stratum_1d(&self, emission_idx: usize, dim: usize, offset: f32) -> f32 {
+        let count = self.count.max(1);
+        let cell = permute(
+            emission_idx,
+            count,
+            SCALAR_STRATUM_STRIDES[dim],
+            self.scalar_stratum_offsets[dim],
+        );
+        // Rounding may produce exactly one for large counts
+        wrap((cell as f32 + offset) / count as f32)
+    }
+
+    /// Places a point at the given offset inside the grid cell assigned to the
+    /// emission with the given index.
+    fn stratum(&self, emission_idx: usize, pair: usize, offset: (f32, f32)) -> (f32, f32) {
+        let count = self.count.max(1);
+        let columns = (count as f32).sqrt().ceil() as usize;
+        let rows = (count + columns - 1) / columns;
+
+        let cell = permute(
+            emission_idx,
+            count,
+            STRATUM_STRIDES[pair],
+            self.stratum_offsets[pair],
+        );
+
+        // The last row may have fewer cells, stretch them across the full width
+        let row = cell / columns;
+        let row_columns = if row + 1 == rows {
+            count - row * columns
+        } else {
+            columns
+        };
+
+        (
+            ((cell % columns) as f32 + offset.0) / row_columns as f32,
+            (row as f32 + offset.1) / rows as f32,
+        )
+    }
+}
+
+/// Maps the given emission index to one of `count` strata, permuting them with the given
+/// stride and offset so different purposes get differently ordered strata. Falls back to
+/// no permutation if the stride happens to share factors with the count.
+fn permute(emission_idx: usize, count: usize, stride: usize, offset: usize) -> usize {
+    let stride = if count % stride == 0 { 1 } else { stride };
+    let cell = ((emission_idx % count) as u64 * stride as u64 + offset as u64) % count as u64;
+    cell as usize
+}
+
+/// Mirrors the digits of the given index in the given base at the decimal point.
+fn radical_inverse(mut idx: usize, base: usize) -> f32 {
+    let inverse_base = 1.0 / base as f64;
+    let mut factor = inverse_base;
+    let mut result = 0.0;
+
+    while idx > 0 {
+        result += (idx % base) as f64 * factor;
+        idx /= base;
+        factor *= inverse_base;
+    }
+
+    result as f32
+}
+
+/// Wraps the given number into `[0, 1)`.
+fn wrap(x: f32) -> f32 {
+    let wrapped = x - x.floor();
+    // Rounding may produce exactly one
+    if wrapped >= 1.0 {
+        0.0
+    } else {
+        wrapped
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_strata_cover_grid() {
+        // Three by two grid, with the two cells of the last row stretched to full width
+        let sampler = EmissionSampler::new(EmissionSampling::Stratified, 5);
+        let mut samples: Vec<(f32, f32)> = (0..5).map(|idx| sampler.sample(idx, 0)).collect();
+        samples.sort_by(|a, b| (a.1, a.0).partial_cmp(&(b.1, b.0)).unwrap());
+
+        let expected = [
+            (1.0 / 6.0, 0.25),
+            (0.5, 0.25),
+            (5.0 / 6.0, 0.25),
+            (0.25, 0.75),
+            (0.75, 0.75),
+        ];
+        for (sample, expected) in samples.iter().zip(expected.iter()) {
+            assert_relative_eq!(sample.0, expected.0, epsilon = 0.0001);
+            assert_relative_eq!(sample.1, expected.1, epsilon = 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_large_counts_do_not_overflow() {
+        let sampler = EmissionSampler::new(EmissionSampling::Jittered, 4_000_000_000);
+        let (u, v) = sampler.sample(3_999_999_999, 1);
+        assert!(u >= 0.0 && u < 1.0 && v >= 0.0 && v < 1.0);
+        let w = sampler.sample_1d(3_999_999_999, 0);
+        assert!(w >= 0.0 && w < 1.0);
+    }
+
+    #[test]
+    fn test_scalar_strata_cover_interval() {
+        let sampler = EmissionSampler::new(EmissionSampling::Stratified, 100);
+        let mut samples: Vec<f32> = (0..100).map(|idx| sampler.sample_1d(idx, 0)).collect();
+        samples.sort_by(|a, b| a.partial_cmp(b).unwrap());
+
+        for (idx, sample) in samples.iter().enumerate() {
+            assert_relative_eq!(*sample, (idx as f32 + 0.5) / 100.0, epsilon = 0.0001);
+        }
+    }
+}
@@ -0,0 +1,34 @@
+//! Triangle fixtures shared by the unit tests of multiple modules.
+
+use geom::prelude::*;
+use geom::{TupleTriangle, Vec2, Vec3, Vertex};
+
+/// Two by two quad on the X/Z plane, facing up.
+pub fn x_z_quad() -> Vec<TupleTriangle<Vertex>> {
+    quad(
+        Vec3::new(-1.0, 0.0, 1.0),
+        Vec3::new(1.0, 0.0, 1.0),
+        Vec3::new(1.0, 0.0, -1.0),
+        Vec3::new(-1.0, 0.0, -1.0),
+    )
+}
+
+/// Two triangles spanning the quad with the given corners in counter-clockwise order.
+pub fn quad(a: Vec3, b: Vec3, c: Vec3, d: Vec3) -> Vec<TupleTriangle<Vertex>> {
+    let normal = (b - a).cross(c - a).normalize();
+    let vertex = |position, texcoords| Vertex {
+        position,
+        normal,
+        texcoords,
+    };
+
+    let a = vertex(a, Vec2::new(0.0, 0.0));
+    let b = vertex(b, Vec2::new(1.0, 0.0));
+    let c = vertex(c, Vec2::new(1.0, 1.0));
+    let d = vertex(d, Vec2::new(0.0, 1.0));
+
+    vec![
+        TupleTriangle(a.clone(), b, c.clone()),
+        TupleTriangle(a, c, d),
+    ]
+}
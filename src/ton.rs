@@ -1,9 +1,21 @@
-use geom::{Interpolation, Position, TangentSpace, TupleTriangle, Vec3, Vertex};
-use sampling::{TriangleBins, Uniform, UnitHemisphere, UnitSphere};
+use direction;
+use distribution::Distribution;
+use geom::prelude::*;
+use geom::{Aabb, Interpolation, Position, TangentSpace, TupleTriangle, Vec3, Vertex};
+use motion::{MotionMultipliers, MotionType, TransferMultipliers};
+use rand::{self, Rng};
 use scene::{Entity, Mesh};
-use std::f32::EPSILON;
-use std::iter;
+use std::f32::consts::{FRAC_PI_2, PI};
+use std::f32::{EPSILON, INFINITY};
+use std::fmt;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use stratify::{EmissionSampler, EmissionSampling};
+
+/// Relative distance between the bounding sphere of the scene and the disk of directional
+/// sources, so tons start outside of the scene.
+const DISK_MARGIN: f32 = 0.01;
 
 #[derive(Debug, Clone)]
 pub struct Ton {
@@ -18,14 +30,35 @@ pub struct Ton {
     pub interaction_radius: f32,
     /// Determines the height of a vertical bounce
     pub parabola_height: f32,
+    /// Linear drag coefficient in 1/s, dragging the ton along with the wind
+    /// on parabolic trajectories
+    pub drag: f32,
     /// Distance of a flow event
     pub flow_distance: f32,
     /// Flow direction calculation method
     pub flow_direction: FlowDirection,
     /// Amount of substances currently being carried by this ton
     pub substances: Vec<f32>,
+    /// Number of tons this ton stands for, scaling every transfer of substance between
+    /// the ton and the surface, e.g. to compensate for restricted emission directions
+    pub weight: f32,
     /// Factor by which the gammaton picks up material from surfels
     pub pickup_rates: Vec<f32>,
+    /// Scales pickup and deposition rates depending on the motion type the
+    /// ton continues with after a contact
+    pub motion_multipliers: MotionMultipliers,
+    /// Exponential decay constant of each substance per unit of travelled distance, so
+    /// a substance shrinks by a factor of `exp(-rate * distance)`, empty if nothing
+    /// evaporates
+    pub evaporation_rates: Vec<f32>,
+    /// Settles the ton on the next contact after travelling this far
+    pub max_path_length: f32,
+    /// Distance travelled since emission
+    pub path_length: f32,
+    /// Number of flow steps the ton has been hanging at an edge, about to drip off
+    pub hanging_steps: usize,
+    /// Number of splashes in a row that resulted in this ton
+    pub splash_generation: usize,
 }
 
 /// Determines method for determination of flow direction based on a hit.
@@ -37,24 +70,384 @@ pub enum FlowDirection {
     /// Projects the associated normalized vector onto the tangential plane to obtain
     /// flow direction.
     Static(Vec3),
+    /// Projects the direction of gravity onto the tangential plane, so tons run downhill.
+    Gravity,
+    /// Projects the direction of the given flow field at the hit point onto the
+    /// tangential plane.
+    Field(FlowField),
+}
+
+/// A spatially varying direction for flowing tons.
+#[derive(Clone)]
+pub enum FlowField {
+    /// Evaluated for each vertex of the hit triangle and interpolated at the hit point,
+    /// e.g. to derive flow directions from vertex attributes.
+    Vertex(Arc<dyn Fn(&Vertex) -> Vec3 + Send + Sync>),
+    /// Evaluated at the hit point.
+    Procedural(Arc<dyn Fn(Vec3) -> Vec3 + Send + Sync>),
+}
+
+impl FlowField {
+    /// Gets the unprojected flow direction at the given point on the given triangle.
+    pub fn direction_at(&self, triangle: &TupleTriangle<Vertex>, point: Vec3) -> Vec3 {
+        match self {
+            &FlowField::Vertex(ref field) => triangle.interpolate_at(point, |v| field(v)),
+            &FlowField::Procedural(ref field) => field(point),
+        }
+    }
+}
+
+impl fmt::Debug for FlowField {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            &FlowField::Vertex(_) => write!(f, "Vertex(..)"),
+            &FlowField::Procedural(_) => write!(f, "Procedural(..)"),
+        }
+    }
 }
 
-// TODO the sampling should be stratified, e.g. by subdividing the possible directions into patches and ensuring every one gets its turn
 enum Shape {
     /// A point source shooting equally in all directions
     Point { position: Vec3 },
-    /// A hemispherical source aligned with the y axis shooting inward.
-    Hemisphere {
-        /// The center of the bottom disk of the hemisphere
+    /// A dome shooting inward, e.g. for sky-like illumination.
+    Dome {
+        /// The center of the bottom disk of the dome
         center: Vec3,
         /// Distance from the center for ray origins
         radius: f32,
+        /// Normalized direction from the center to the top of the dome
+        up: Vec3,
+        distribution: DomeDistribution,
+        /// Scene bounds for distributions aiming into the scene, set when the source
+        /// is added to a simulation
+        bounds: Option<Aabb>,
     },
-    /// Shoots from the given mesh in interpolated normal direction
+    /// Shoots from the given mesh in interpolated normal direction, emitting more tons
+    /// where the vertex weights are higher, if any
     Mesh {
-        triangles: TriangleBins<TupleTriangle<Vertex>>,
+        triangles: MeshTriangles,
         diffuse: bool,
     },
+    /// Shoots parallel rays from a disk perpendicular to the direction, e.g. for sun or rain.
+    Directional {
+        /// Normalized direction of all rays
+        direction: Vec3,
+        /// Center and radius of the disk, fitted to cover the scene bounds when the
+        /// source is added to a simulation. Emissions are discarded until then.
+        disk: Option<(Vec3, f32)>,
+    },
+    /// Shoots from uniformly distributed points inside an axis-aligned box.
+    Box {
+        min: Vec3,
+        max: Vec3,
+        directions: VolumeDirections,
+    },
+    /// Shoots from uniformly distributed points inside a sphere.
+    Sphere {
+        center: Vec3,
+        radius: f32,
+        directions: VolumeDirections,
+    },
+    /// Shoots from an apex in a cone, e.g. for sprays.
+    Cone {
+        apex: Vec3,
+        /// Normalized axis of the cone
+        direction: Vec3,
+        /// Half opening angle in radians
+        half_angle: f32,
+    },
+}
+
+/// Distribution of the directions that tons are emitted in from a point on a dome.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum DomeDistribution {
+    /// Uniformly distributed in the hemisphere facing inward.
+    InwardUniform,
+    /// Cosine-weighted in the hemisphere facing inward, favoring directions close to
+    /// the inward normal of the dome.
+    InwardCosine,
+    /// Towards a uniformly distributed random point in the scene bounds. Like
+    /// `InwardUniform` until the source is fitted to the scene bounds.
+    SceneBounds,
+    /// Towards the center of the dome.
+    Center,
+}
+
+/// Distribution of the directions that tons are emitted in from points inside a volume.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum VolumeDirections {
+    /// Uniformly distributed in a cone, e.g. slightly jittered downward directions for rain.
+    Cone {
+        /// Normalized axis of the cone
+        direction: Vec3,
+        /// Half opening angle in radians
+        half_angle: f32,
+    },
+    /// Uniformly distributed in all directions, e.g. for fog.
+    Isotropic,
+}
+
+impl VolumeDirections {
+    fn normalized(self) -> Self {
+        match self {
+            VolumeDirections::Cone {
+                direction,
+                half_angle,
+            } => VolumeDirections::Cone {
+                direction: direction.normalize(),
+                half_angle,
+            },
+            VolumeDirections::Isotropic => VolumeDirections::Isotropic,
+        }
+    }
+
+    fn lobe(&self) -> Lobe {
+        match *self {
+            VolumeDirections::Cone {
+                direction,
+                half_angle,
+            } => Lobe::Uniform {
+                axis: direction,
+                half_angle,
+            },
+            VolumeDirections::Isotropic => Lobe::Uniform {
+                axis: Vec3::new(0.0, 0.0, 1.0),
+                half_angle: PI,
+            },
+        }
+    }
+}
+
+/// Emission weight of a vertex of a mesh source, e.g. read from a vertex color channel.
+pub type VertexWeight = Arc<dyn Fn(&Vertex) -> f32 + Send + Sync>;
+
+/// Substances carried by tons emitted at a vertex of a mesh source.
+pub type VertexSubstances = Arc<dyn Fn(&Vertex) -> Vec<f32> + Send + Sync>;
+
+/// Triangles of a mesh source, selected proportionally to their area, or if weighted,
+/// split into one bin per vertex that is selected proportionally to the area times the
+/// emission weight of the vertex.
+///
+/// Replaces `TriangleBins` from the sampling crate, which only selects triangles with
+/// its own random numbers, so selection can follow the configured emission sampling.
+struct MeshTriangles {
+    triangles: Vec<TupleTriangle<Vertex>>,
+    /// Whether there is one bin per vertex of each triangle instead of one per triangle
+    weighted: bool,
+    /// Running sum of the selection weights of the bins
+    cumulative_weights: Vec<f32>,
+}
+
+impl MeshTriangles {
+    fn new<I>(triangles: I, weight: Option<&dyn Fn(&Vertex) -> f32>) -> Self
+    where
+        I: IntoIterator<Item = TupleTriangle<Vertex>>,
+    {
+        let triangles: Vec<TupleTriangle<Vertex>> = triangles.into_iter().collect();
+
+        let mut total = 0.0;
+        let mut cumulative_weights = Vec::new();
+        for t in &triangles {
+            let (a, b, c) = t.positions();
+            let area = 0.5 * (b - a).cross(c - a).magnitude();
+            match weight {
+                // The interpolated weight is the sum of the vertex weights times the
+                // barycentric coordinates, each of which integrates to a third of the area
+                Some(weight) => {
+                    for vertex in &[&t.0, &t.1, &t.2] {
+                        total += area * weight(vertex).max(0.0) / 3.0;
+                        cumulative_weights.push(total);
+                    }
+                }
+                None => {
+                    total += area;
+                    cumulative_weights.push(total);
+                }
+            }
+        }
+
+        MeshTriangles {
+            triangles,
+            weighted: weight.is_some(),
+            cumulative_weights,
+        }
+    }
+
+    fn total_weight(&self) -> f32 {
+        self.cumulative_weights.last().cloned().unwrap_or(0.0)
+    }
+
+    /// Selects a triangle with a number in `[0, 1)` and a position on it with a pair of
+    /// numbers in `[0, 1)`, with a density proportional to the interpolated vertex weights
+    /// if weighted.
+    ///
+    /// Returns the triangle and barycentric coordinates of the position.
+    fn sample(&self, selection: f32, position: (f32, f32)) -> (&TupleTriangle<Vertex>, Vec3) {
+        let bin = self.select(selection);
+        if self.weighted {
+            (
+                &self.triangles[bin / 3],
+                vertex_weighted_barycentric(bin % 3, position),
+            )
+        } else {
+            (&self.triangles[bin], triangle_barycentric(position))
+        }
+    }
+
+    /// Finds the first bin with a running sum of weights larger than the given fraction
+    /// of the total weight.
+    fn select(&self, u: f32) -> usize {
+        let target = u * self.total_weight();
+        let mut low = 0;
+        let mut high = self.cumulative_weights.len() - 1;
+
+        while low < high {
+            let mid = (low + high) / 2;
+            if self.cumulative_weights[mid] > target {
+                high = mid;
+            } else {
+                low = mid + 1;
+            }
+        }
+
+        // Rounding may reach the total, skip trailing bins without weight
+        while low > 0 && self.cumulative_weights[low] == self.cumulative_weights[low - 1] {
+            low -= 1;
+        }
+
+        low
+    }
+}
+
+/// Distribution of emission directions at a single origin.
+enum Lobe {
+    /// Uniformly distributed in the cone around the normalized axis with the given half
+    /// opening angle in radians
+    Uniform { axis: Vec3, half_angle: f32 },
+    /// Cosine-weighted in the hemisphere around the normalized axis
+    Cosine { axis: Vec3 },
+    /// Always the same direction
+    Fixed(Vec3),
+}
+
+impl Lobe {
+    /// Maps two numbers in `[0, 1)` to a direction of this distribution.
+    fn sample(&self, (u, v): (f32, f32)) -> Vec3 {
+        match *self {
+            Lobe::Uniform { axis, half_angle } => direction::cone(axis, half_angle, u, v),
+            Lobe::Cosine { axis } => direction::cosine_weighted(axis, u, v),
+            Lobe::Fixed(direction) => direction,
+        }
+    }
+
+    /// Samples a direction towards the sphere with the given center and radius as seen from
+    /// the given origin, together with the ratio of the probability densities of the
+    /// direction with and without the restriction.
+    ///
+    /// The weight is zero for directions this distribution never emits in. Falls back to
+    /// unrestricted sampling with weight one for fixed directions, origins inside the sphere
+    /// and spheres covering more directions than the distribution.
+    fn sample_towards(
+        &self,
+        origin: Vec3,
+        center: Vec3,
+        radius: f32,
+        sample: (f32, f32),
+    ) -> (Vec3, f32) {
+        let to_center = center - origin;
+        let distance = to_center.magnitude();
+        let solid_angle = self.solid_angle();
+
+        if distance <= radius || solid_angle == 0.0 {
+            return (self.sample(sample), 1.0);
+        }
+
+        let sin_half_angle = radius / distance;
+        let cos_half_angle = (1.0 - sin_half_angle * sin_half_angle).sqrt();
+        let cone_solid_angle = 2.0 * PI * (1.0 - cos_half_angle);
+        if cone_solid_angle >= solid_angle {
+            return (self.sample(sample), 1.0);
+        }
+
+        let direction = direction::cone(
+            to_center / distance,
+            sin_half_angle.asin(),
+            sample.0,
+            sample.1,
+        );
+        (direction, self.density(direction) * cone_solid_angle)
+    }
+
+    /// Solid angle of the directions this distribution emits in, zero for fixed directions.
+    fn solid_angle(&self) -> f32 {
+        match *self {
+            Lobe::Uniform { half_angle, .. } => 2.0 * PI * (1.0 - half_angle.cos()),
+            Lobe::Cosine { .. } => 2.0 * PI,
+            Lobe::Fixed(_) => 0.0,
+        }
+    }
+
+    /// Probability density of the given normalized direction per solid angle, undefined for
+    /// fixed directions.
+    fn density(&self, direction: Vec3) -> f32 {
+        match *self {
+            Lobe::Uniform { axis, half_angle } => {
+                if direction.dot(axis) >= half_angle.cos() {
+                    self.solid_angle().recip()
+                } else {
+                    0.0
+                }
+            }
+            Lobe::Cosine { axis } => direction.dot(axis).max(0.0) / PI,
+            Lobe::Fixed(_) => 0.0,
+        }
+    }
+}
+
+/// Distributions for ton parameters, `None` for parameters that are the same for all
+/// tons of a source.
+#[derive(Debug, Clone, Default)]
+struct Variations {
+    p_straight: Option<Distribution>,
+    p_parabolic: Option<Distribution>,
+    p_flow: Option<Distribution>,
+    interaction_radius: Option<Distribution>,
+    parabola_height: Option<Distribution>,
+    flow_distance: Option<Distribution>,
+    /// Factor for all substances
+    substance_load: Option<Distribution>,
+}
+
+impl Variations {
+    /// Replaces the varying parameters of the given ton with independent samples.
+    fn apply(&self, ton: &mut Ton) {
+        let mut rng = rand::thread_rng();
+        let mut sample = |distribution: &Option<Distribution>, value: &mut f32| {
+            if let &Some(ref distribution) = distribution {
+                *value = distribution.sample(&mut rng);
+            }
+        };
+
+        sample(&self.p_straight, &mut ton.p_straight);
+        sample(&self.p_parabolic, &mut ton.p_parabolic);
+        sample(&self.p_flow, &mut ton.p_flow);
+        sample(&self.interaction_radius, &mut ton.interaction_radius);
+        sample(&self.parabola_height, &mut ton.parabola_height);
+        sample(&self.flow_distance, &mut ton.flow_distance);
+
+        let mut load = 1.0;
+        sample(&self.substance_load, &mut load);
+        ton.substances.iter_mut().for_each(|s| *s *= load);
+    }
+}
+
+/// Region that emission directions are restricted to.
+#[derive(Clone, Copy)]
+enum Target {
+    /// Bounding sphere of the scene, known after fitting the source to the scene bounds
+    Scene(Option<(Vec3, f32)>),
+    /// Sphere with the given center and radius
+    Region(Vec3, f32),
 }
 
 pub struct TonSource {
@@ -62,6 +455,23 @@ pub struct TonSource {
     shape: Shape,
     proto_ton: Ton,
     emission_count: usize,
+    /// Distribution of the samples that positions and directions are derived from
+    sampling: EmissionSampling,
+    /// Factor for the wind velocity at the origin that is added to the emission
+    /// direction before normalizing
+    wind_bias: f32,
+    /// Region to restrict emission directions to, if any
+    target: Option<Target>,
+    /// Substances of tons emitted from mesh sources, interpolated between the
+    /// vertices instead of the substances of the prototype ton
+    vertex_substances: Option<VertexSubstances>,
+    /// Distributions of ton parameters that vary between emissions
+    variations: Variations,
+    /// Speed of tons thrown on a parabolic trajectory at emission, `None` to emit
+    /// in straight lines
+    initial_speed: Option<f32>,
+    /// Whether emitting before fitting to the scene bounds was already warned about
+    warned_unfitted: AtomicBool,
 }
 
 pub struct TonSourceBuilder {
@@ -72,59 +482,303 @@ pub struct TonEmission {
     pub origin: Vec3,
     pub direction: Vec3,
     pub ton: Ton,
+    /// Factor that the weight of the ton was scaled with to compensate for
+    /// restricted emission directions, zero if the ton should be discarded
+    pub weight: f32,
 }
 
 impl TonSource {
+    /// Emits a single ton from independent random samples, regardless of the
+    /// configured sampling strategy.
     pub fn emit_one(&self) -> TonEmission {
-        let ton = self.proto_ton.clone();
-        let (origin, direction) = match &self.shape {
+        self.warn_if_unfitted();
+        self.emit_from_samples(
+            &|_| {
+                let mut rng = rand::thread_rng();
+                (rng.gen(), rng.gen())
+            },
+            &|_| rand::thread_rng().gen(),
+        )
+    }
+
+    /// Prepares the samples for one batch of emissions, e.g. one iteration, with the
+    /// configured sampling strategy.
+    pub fn sampler(&self) -> EmissionSampler {
+        self.warn_if_unfitted();
+        EmissionSampler::new(self.sampling, self.emission_count)
+    }
+
+    /// Emits the ton with the given index in the batch of the given sampler.
+    pub fn emit_nth(&self, sampler: &EmissionSampler, emission_idx: usize) -> TonEmission {
+        self.emit_from_samples(&|pair| sampler.sample(emission_idx, pair), &|dim| {
+            sampler.sample_1d(emission_idx, dim)
+        })
+    }
+
+    /// Emits a ton with positions and directions mapped from pairs of numbers in `[0, 1)`
+    /// that are obtained from the given function by index, e.g. the position on a mesh from
+    /// the first pair and the direction from the second pair. Coordinates that are not part
+    /// of a pair, like the triangle of a mesh or the depth in a volume, are obtained from
+    /// the given scalar function.
+    fn emit_from_samples(
+        &self,
+        sample: &dyn Fn(usize) -> (f32, f32),
+        sample_1d: &dyn Fn(usize) -> f32,
+    ) -> TonEmission {
+        let up = Vec3::new(0.0, 0.0, 1.0);
+        let primary = sample(0);
+        let mut mesh_substances = None;
+        let mut unfitted = false;
+        let (origin, lobe) = match &self.shape {
             &Shape::Point { position } => (
                 position.clone(),
-                // Random position on the unit sphere
-                UnitSphere.uniform(),
+                // Position on the unit sphere
+                Lobe::Uniform {
+                    axis: up,
+                    half_angle: PI,
+                },
             ),
-            &Shape::Hemisphere { center, radius } => {
-                let unit = UnitHemisphere::PosZ.uniform();
+            &Shape::Dome {
+                center,
+                radius,
+                up,
+                distribution,
+                ref bounds,
+            } => {
+                let unit = direction::cone(up, FRAC_PI_2, primary.0, primary.1);
                 let origin = center + radius * unit;
-                // REVIEW wait, should they really all be flying towards the center?
-                let direction = -unit;
-                (origin, direction)
+                let inward = -unit;
+
+                let lobe = match distribution {
+                    DomeDistribution::InwardUniform => Lobe::Uniform {
+                        axis: inward,
+                        half_angle: FRAC_PI_2,
+                    },
+                    DomeDistribution::InwardCosine => Lobe::Cosine { axis: inward },
+                    DomeDistribution::SceneBounds if bounds.is_some() => {
+                        let bounds = bounds.as_ref().unwrap();
+                        let secondary = sample(1);
+                        let extent = bounds.max - bounds.min;
+                        let target = bounds.min
+                            + Vec3::new(
+                                secondary.0 * extent.x,
+                                secondary.1 * extent.y,
+                                sample_1d(0) * extent.z,
+                            );
+                        Lobe::Fixed((target - origin).normalize())
+                    }
+                    DomeDistribution::Center => Lobe::Fixed(inward),
+                    // Shoot inward uniformly until fitted to the scene bounds
+                    DomeDistribution::SceneBounds => Lobe::Uniform {
+                        axis: inward,
+                        half_angle: FRAC_PI_2,
+                    },
+                };
+
+                (origin, lobe)
             }
             &Shape::Mesh {
                 ref triangles,
                 diffuse,
             } => {
-                // Interpolate a vertex on a random position on a randomly selected triangle (weighted by area)
-                let tri = triangles.sample();
-                let vtx = tri.interpolate_at(tri.uniform(), |v| v.clone());
-
-                let direction = if diffuse {
-                    tri.tangent_to_world_matrix() * UnitHemisphere::PosZ.uniform()
-                } else {
-                    vtx.normal
-                };
-
-                let origin = vtx.position + direction * EPSILON;
-                (origin, direction)
+                // Interpolate a vertex on a sampled position on a triangle selected by
+                // weighted area through the selection sample
+                let (tri, barycentric) = triangles.sample(sample_1d(0), primary);
+                mesh_substances = self.interpolate_substances(tri, barycentric);
+                Self::emit_from_triangle(tri, barycentric, diffuse)
             }
+            &Shape::Directional { direction, disk } => match disk {
+                Some((center, radius)) => (
+                    disk_point(center, direction, radius, primary),
+                    Lobe::Fixed(direction),
+                ),
+                // Nowhere to shoot from without a disk, discard the emission
+                None => {
+                    unfitted = true;
+                    (Vec3::new(0.0, 0.0, 0.0), Lobe::Fixed(direction))
+                }
+            },
+            &Shape::Box {
+                min,
+                max,
+                directions,
+            } => {
+                let (u, v) = sample(0);
+                let w = sample_1d(0);
+                let origin = Vec3::new(
+                    min.x + u * (max.x - min.x),
+                    min.y + v * (max.y - min.y),
+                    min.z + w * (max.z - min.z),
+                );
+                (origin, directions.lobe())
+            }
+            &Shape::Sphere {
+                center,
+                radius,
+                directions,
+            } => {
+                let (u, v) = sample(0);
+                let w = sample_1d(0);
+                // Cube root keeps the density uniform over the volume
+                let distance = radius * w.cbrt();
+                let origin = center + distance * direction::cone(up, PI, u, v);
+                (origin, directions.lobe())
+            }
+            &Shape::Cone {
+                apex,
+                direction,
+                half_angle,
+            } => (
+                apex,
+                Lobe::Uniform {
+                    axis: direction,
+                    half_angle,
+                },
+            ),
+        };
+
+        let (direction, weight) = match self.target_sphere() {
+            Some((center, radius)) => lobe.sample_towards(origin, center, radius, sample(1)),
+            None => (lobe.sample(sample(1)), 1.0),
         };
+        let weight = if unfitted { 0.0 } else { weight };
+
+        // Weight the ton, so restricting directions does not bias transport
+        let mut ton = self.proto_ton.clone();
+        if let Some(substances) = mesh_substances {
+            ton.substances = substances;
+        }
+        self.variations.apply(&mut ton);
+        ton.weight *= weight;
 
         TonEmission {
-            origin,
+            // Nudge away from emitting surfaces, e.g. of mesh sources
+            origin: origin + direction * EPSILON,
             direction,
             ton,
+            weight,
+        }
+    }
+
+    /// Gets the position on the given triangle at the given barycentric coordinates and
+    /// the distribution of directions from there.
+    fn emit_from_triangle(
+        tri: &TupleTriangle<Vertex>,
+        barycentric: Vec3,
+        diffuse: bool,
+    ) -> (Vec3, Lobe) {
+        let (a, b, c) = tri.positions();
+        let point = a * barycentric.x + b * barycentric.y + c * barycentric.z;
+        let vtx = tri.interpolate_at(point, |v| v.clone());
+
+        let lobe = if diffuse {
+            Lobe::Uniform {
+                axis: tri.normal(),
+                half_angle: FRAC_PI_2,
+            }
+        } else {
+            Lobe::Fixed(vtx.normal)
+        };
+
+        (vtx.position, lobe)
+    }
+
+    /// Interpolates the per-vertex substances at the given barycentric coordinates on the
+    /// given triangle, if configured.
+    fn interpolate_substances(
+        &self,
+        tri: &TupleTriangle<Vertex>,
+        barycentric: Vec3,
+    ) -> Option<Vec<f32>> {
+        self.vertex_substances.as_ref().map(|substances| {
+            let (a, b, c) = (substances(&tri.0), substances(&tri.1), substances(&tri.2));
+            a.iter()
+                .zip(b.iter())
+                .zip(c.iter())
+                .map(|((a, b), c)| a * barycentric.x + b * barycentric.y + c * barycentric.z)
+                .collect()
+        })
+    }
+
+    /// Gets the bounding sphere of the region emissions are aimed at, if any.
+    fn target_sphere(&self) -> Option<(Vec3, f32)> {
+        match self.target {
+            Some(Target::Scene(sphere)) => sphere,
+            Some(Target::Region(center, radius)) => Some((center, radius)),
+            None => None,
         }
     }
 
     pub fn emit<'a>(&'a self) -> impl Iterator<Item = TonEmission> + 'a {
-        iter::repeat(self)
-            .take(self.emission_count)
-            .map(Self::emit_one)
+        let sampler = self.sampler();
+        (0..self.emission_count).map(move |idx| self.emit_nth(&sampler, idx))
     }
 
     pub fn emission_count(&self) -> usize {
         self.emission_count
     }
+
+    pub fn wind_bias(&self) -> f32 {
+        self.wind_bias
+    }
+
+    /// Speed that tons are thrown with at emission, or `None` if they start in a
+    /// straight line.
+    pub fn initial_speed(&self) -> Option<f32> {
+        self.initial_speed
+    }
+
+    /// Whether the shape and target were fitted to the scene bounds, if they depend on them.
+    pub(crate) fn is_fitted(&self) -> bool {
+        let shape_fitted = match self.shape {
+            Shape::Directional { disk: None, .. } => false,
+            Shape::Dome {
+                distribution: DomeDistribution::SceneBounds,
+                bounds: None,
+                ..
+            } => false,
+            _ => true,
+        };
+
+        match self.target {
+            Some(Target::Scene(None)) => false,
+            _ => shape_fitted,
+        }
+    }
+
+    /// Warns the first time an unfitted source is emitted from.
+    fn warn_if_unfitted(&self) {
+        if !self.is_fitted() && !self.warned_unfitted.swap(true, Ordering::Relaxed) {
+            warn!("Emitting from a source that was not fitted to scene bounds, directional sources discard emissions, domes shoot inward uniformly and directions are not restricted to the scene instead. Add it to a simulation or fit it in the builder first.");
+        }
+    }
+
+    /// Adapts shapes that depend on the scene, like directional sources, to the given
+    /// scene bounds.
+    pub(crate) fn fit_to_bounds(&mut self, bounds: &Aabb) {
+        match self.shape {
+            Shape::Directional {
+                direction,
+                ref mut disk,
+            } => {
+                // Place the disk just outside the bounding sphere, facing into the scene
+                let center = 0.5 * (bounds.min + bounds.max);
+                let radius = 0.5 * (bounds.max - bounds.min).magnitude();
+                *disk = Some((center - direction * radius * (1.0 + DISK_MARGIN), radius));
+            }
+            Shape::Dome {
+                bounds: ref mut dome_bounds,
+                ..
+            } => *dome_bounds = Some(bounds.clone()),
+            _ => (),
+        }
+
+        if let Some(Target::Scene(ref mut sphere)) = self.target {
+            let center = 0.5 * (bounds.min + bounds.max);
+            let radius = 0.5 * (bounds.max - bounds.min).magnitude();
+            *sphere = Some((center, radius));
+        }
+    }
 }
 
 impl TonSourceBuilder {
@@ -132,6 +786,13 @@ impl TonSourceBuilder {
         TonSourceBuilder {
             source: TonSource {
                 emission_count: 10000,
+                sampling: EmissionSampling::Random,
+                wind_bias: 0.0,
+                target: None,
+                vertex_substances: None,
+                variations: Default::default(),
+                initial_speed: None,
+                warned_unfitted: AtomicBool::new(false),
                 shape: Shape::Point {
                     position: Vec3::new(0.0, 0.0, 0.0),
                 },
@@ -140,11 +801,19 @@ impl TonSourceBuilder {
                     p_parabolic: 0.0,
                     p_flow: 0.0,
                     substances: Vec::new(),
+                    weight: 1.0,
                     interaction_radius: 0.1,
                     parabola_height: 0.05,
+                    drag: 0.0,
                     flow_distance: 0.02,
                     flow_direction: FlowDirection::Incident,
                     pickup_rates: Vec::new(),
+                    motion_multipliers: Default::default(),
+                    evaporation_rates: Vec::new(),
+                    max_path_length: INFINITY,
+                    path_length: 0.0,
+                    hanging_steps: 0,
+                    splash_generation: 0,
                 },
             },
         }
@@ -157,8 +826,86 @@ impl TonSourceBuilder {
         self
     }
 
-    pub fn hemisphere_shaped(mut self, center: Vec3, radius: f32) -> TonSourceBuilder {
-        self.source.shape = Shape::Hemisphere { center, radius };
+    /// Shoots from a hemisphere around the z axis with the given center and radius,
+    /// towards the center.
+    pub fn hemisphere_shaped(self, center: Vec3, radius: f32) -> TonSourceBuilder {
+        self.dome_shaped(
+            center,
+            radius,
+            Vec3::new(0.0, 0.0, 1.0),
+            DomeDistribution::Center,
+        )
+    }
+
+    /// Shoots inward from a dome with the given center and radius, around the given
+    /// up axis, with the given distribution of directions at each point of the dome.
+    pub fn dome_shaped(
+        mut self,
+        center: Vec3,
+        radius: f32,
+        up: Vec3,
+        distribution: DomeDistribution,
+    ) -> TonSourceBuilder {
+        self.source.shape = Shape::Dome {
+            center,
+            radius,
+            up: up.normalize(),
+            distribution,
+            bounds: None,
+        };
+        self
+    }
+
+    /// Shoots parallel rays in the given direction from a disk that covers the
+    /// scene bounds, like sunlight or rain.
+    pub fn directional_shaped(mut self, direction: Vec3) -> TonSourceBuilder {
+        self.source.shape = Shape::Directional {
+            direction: direction.normalize(),
+            disk: None,
+        };
+        self
+    }
+
+    /// Shoots from uniformly distributed points inside the axis-aligned box with the
+    /// given corners, in directions with the given distribution.
+    pub fn box_shaped(
+        mut self,
+        min: Vec3,
+        max: Vec3,
+        directions: VolumeDirections,
+    ) -> TonSourceBuilder {
+        self.source.shape = Shape::Box {
+            min,
+            max,
+            directions: directions.normalized(),
+        };
+        self
+    }
+
+    /// Shoots from uniformly distributed points inside the sphere with the given
+    /// center and radius, in directions with the given distribution.
+    pub fn sphere_shaped(
+        mut self,
+        center: Vec3,
+        radius: f32,
+        directions: VolumeDirections,
+    ) -> TonSourceBuilder {
+        self.source.shape = Shape::Sphere {
+            center,
+            radius,
+            directions: directions.normalized(),
+        };
+        self
+    }
+
+    /// Shoots from the given apex in a cone around the given direction with the given
+    /// half opening angle in radians, like a spray.
+    pub fn cone_shaped(mut self, apex: Vec3, direction: Vec3, half_angle: f32) -> TonSourceBuilder {
+        self.source.shape = Shape::Cone {
+            apex,
+            direction: direction.normalize(),
+            half_angle,
+        };
         self
     }
 
@@ -166,26 +913,110 @@ impl TonSourceBuilder {
         self.mesh_shaped(&entity.mesh, diffuse)
     }
 
-    pub fn mesh_shaped<'a, T, M, V>(mut self, mesh: &'a T, diffuse: bool) -> TonSourceBuilder
+    pub fn mesh_shaped<'a, T, M, V>(self, mesh: &'a T, diffuse: bool) -> TonSourceBuilder
     where
         T: Deref<Target = M>,
         M: Mesh<'a, Vertex = V> + 'a,
         V: Position,
-        TriangleBins<TupleTriangle<Vertex>>: iter::FromIterator<TupleTriangle<V>>,
+        TupleTriangle<V>: Into<TupleTriangle<Vertex>>,
+    {
+        self.triangles_shaped(mesh.triangles().map(Into::into), diffuse)
+    }
+
+    /// Like `mesh_shaped`, but shoots from the given triangles.
+    pub fn triangles_shaped<I>(mut self, triangles: I, diffuse: bool) -> TonSourceBuilder
+    where
+        I: IntoIterator<Item = TupleTriangle<Vertex>>,
     {
         self.source.shape = Shape::Mesh {
-            triangles: mesh.triangles().collect(),
+            triangles: MeshTriangles::new(triangles, None),
             diffuse,
         };
 
         self
     }
 
+    /// Like `entity_shaped`, but emits tons with a density proportional to the given
+    /// weight interpolated between vertices.
+    pub fn weighted_entity_shaped(
+        self,
+        entity: &Entity,
+        diffuse: bool,
+        weight: VertexWeight,
+    ) -> TonSourceBuilder {
+        self.weighted_mesh_shaped(&entity.mesh, diffuse, weight)
+    }
+
+    /// Like `mesh_shaped`, but emits tons with a density proportional to the given
+    /// weight interpolated between vertices. Negative weights count as zero.
+    pub fn weighted_mesh_shaped<'a, T, M>(
+        mut self,
+        mesh: &'a T,
+        diffuse: bool,
+        weight: VertexWeight,
+    ) -> TonSourceBuilder
+    where
+        T: Deref<Target = M>,
+        M: Mesh<'a, Vertex = Vertex> + 'a,
+    {
+        self.weighted_triangles_shaped(mesh.triangles(), diffuse, weight)
+    }
+
+    /// Like `weighted_mesh_shaped`, but shoots from the given triangles.
+    pub fn weighted_triangles_shaped<I>(
+        mut self,
+        triangles: I,
+        diffuse: bool,
+        weight: VertexWeight,
+    ) -> TonSourceBuilder
+    where
+        I: IntoIterator<Item = TupleTriangle<Vertex>>,
+    {
+        self.source.shape = Shape::Mesh {
+            triangles: MeshTriangles::new(triangles, Some(&*weight)),
+            diffuse,
+        };
+
+        self
+    }
+
+    /// Lets mesh sources emit tons with the given substances per vertex, interpolated
+    /// at the emission point, instead of the substances set with `substances`. The
+    /// function must return as many substances as there are pickup rates.
+    ///
+    /// Building panics if the source is not mesh shaped, or if the substances of a
+    /// vertex of the mesh do not match the pickup rates.
+    pub fn vertex_substances(mut self, vertex_substances: VertexSubstances) -> TonSourceBuilder {
+        self.source.vertex_substances = Some(vertex_substances);
+        self
+    }
+
     pub fn emission_count(mut self, emission_count: usize) -> TonSourceBuilder {
         self.source.emission_count = emission_count;
         self
     }
 
+    /// Sets the strategy to distribute emission positions and directions, which
+    /// defaults to independent random samples.
+    pub fn emission_sampling(mut self, sampling: EmissionSampling) -> TonSourceBuilder {
+        self.source.sampling = sampling;
+        self
+    }
+
+    /// Restricts emission directions to the bounding sphere of the scene, weighting
+    /// tons to keep transport unbiased.
+    pub fn aim_at_scene(mut self) -> TonSourceBuilder {
+        self.source.target = Some(Target::Scene(None));
+        self
+    }
+
+    /// Restricts emission directions to the sphere with the given center and radius,
+    /// weighting tons to keep transport unbiased.
+    pub fn aim_at_region(mut self, center: Vec3, radius: f32) -> TonSourceBuilder {
+        self.source.target = Some(Target::Region(center, radius));
+        self
+    }
+
     pub fn p_straight(mut self, p_straight: f32) -> TonSourceBuilder {
         self.source.proto_ton.p_straight = p_straight;
         self
@@ -214,6 +1045,84 @@ impl TonSourceBuilder {
         self
     }
 
+    /// Scales pickup and deposition rates of contacts after which the ton
+    /// continues with the given motion type. Both multipliers default to `1.0`.
+    pub fn transfer_multipliers(
+        mut self,
+        motion_type: MotionType,
+        pickup: f32,
+        deposition: f32,
+    ) -> TonSourceBuilder {
+        self.source
+            .proto_ton
+            .motion_multipliers
+            .set(motion_type, TransferMultipliers { pickup, deposition });
+        self
+    }
+
+    /// Sets the rate each substance decays with exponentially per unit of distance
+    /// travelled between contacts, scaling it by `exp(-rate * distance)`.
+    pub fn evaporation_rates<R: IntoIterator<Item = f32>>(
+        mut self,
+        evaporation_rates: R,
+    ) -> TonSourceBuilder {
+        self.source.proto_ton.evaporation_rates = evaporation_rates.into_iter().collect();
+        self
+    }
+
+    /// Settles tons on the first contact after they travelled the given distance
+    /// since emission.
+    pub fn max_path_length(mut self, max_path_length: f32) -> TonSourceBuilder {
+        self.source.proto_ton.max_path_length = max_path_length;
+        self
+    }
+
+    /// Samples the probability of straight motion for each ton from the given distribution.
+    pub fn p_straight_distribution(mut self, distribution: Distribution) -> TonSourceBuilder {
+        self.source.variations.p_straight = Some(distribution);
+        self
+    }
+
+    /// Samples the probability of parabolic motion for each ton from the given distribution.
+    pub fn p_parabolic_distribution(mut self, distribution: Distribution) -> TonSourceBuilder {
+        self.source.variations.p_parabolic = Some(distribution);
+        self
+    }
+
+    /// Samples the probability of flow for each ton from the given distribution.
+    pub fn p_flow_distribution(mut self, distribution: Distribution) -> TonSourceBuilder {
+        self.source.variations.p_flow = Some(distribution);
+        self
+    }
+
+    /// Samples the interaction radius for each ton from the given distribution.
+    pub fn interaction_radius_distribution(
+        mut self,
+        distribution: Distribution,
+    ) -> TonSourceBuilder {
+        self.source.variations.interaction_radius = Some(distribution);
+        self
+    }
+
+    /// Samples the parabola height for each ton from the given distribution.
+    pub fn parabola_height_distribution(mut self, distribution: Distribution) -> TonSourceBuilder {
+        self.source.variations.parabola_height = Some(distribution);
+        self
+    }
+
+    /// Samples the flow distance for each ton from the given distribution.
+    pub fn flow_distance_distribution(mut self, distribution: Distribution) -> TonSourceBuilder {
+        self.source.variations.flow_distance = Some(distribution);
+        self
+    }
+
+    /// Samples a factor for all substances of each ton from the given distribution,
+    /// e.g. to emit droplets of varying size.
+    pub fn substance_load_distribution(mut self, distribution: Distribution) -> TonSourceBuilder {
+        self.source.variations.substance_load = Some(distribution);
+        self
+    }
+
     pub fn interaction_radius(mut self, interaction_radius: f32) -> TonSourceBuilder {
         self.source.proto_ton.interaction_radius = interaction_radius;
         self
@@ -224,6 +1133,28 @@ impl TonSourceBuilder {
         self
     }
 
+    /// Sets the linear drag coefficient in 1/s that drags tons along with the wind
+    /// on parabolic trajectories.
+    pub fn drag(mut self, drag: f32) -> TonSourceBuilder {
+        self.source.proto_ton.drag = drag;
+        self
+    }
+
+    /// Throws tons on a parabolic trajectory with the given speed at emission, subject to
+    /// gravity and, with drag, wind, instead of starting in a straight line.
+    pub fn ballistic(mut self, initial_speed: f32) -> TonSourceBuilder {
+        self.source.initial_speed = Some(initial_speed);
+        self
+    }
+
+    /// Biases emission directions towards the wind, by adding the wind velocity at
+    /// the origin scaled with the given factor to the direction. Directions that the
+    /// wind cancels out stay unbiased.
+    pub fn wind_bias(mut self, wind_bias: f32) -> TonSourceBuilder {
+        self.source.wind_bias = wind_bias;
+        self
+    }
+
     pub fn flow_distance(mut self, flow_distance: f32) -> TonSourceBuilder {
         self.source.proto_ton.flow_distance = flow_distance;
         self
@@ -234,6 +1165,26 @@ impl TonSourceBuilder {
         self
     }
 
+    /// Lets tons flow in the direction of gravity, projected onto the surface.
+    pub fn flow_direction_gravity(mut self) -> TonSourceBuilder {
+        self.source.proto_ton.flow_direction = FlowDirection::Gravity;
+        self
+    }
+
+    /// Lets tons flow in the direction of the given field, projected onto the surface.
+    pub fn flow_direction_field(mut self, flow_field: FlowField) -> TonSourceBuilder {
+        self.source.proto_ton.flow_direction = FlowDirection::Field(flow_field);
+        self
+    }
+
+    /// Fits shapes that depend on the scene, like directional sources, to the given
+    /// scene bounds, e.g. to emit without a simulation. Simulations fit sources that
+    /// were not fitted here to the bounds of their scene when created.
+    pub fn fit_to_bounds(mut self, bounds: &Aabb) -> TonSourceBuilder {
+        self.source.fit_to_bounds(bounds);
+        self
+    }
+
     pub fn build(self) -> TonSource {
         assert_eq!(
             self.source.proto_ton.pickup_rates.len(),
@@ -241,15 +1192,77 @@ impl TonSourceBuilder {
             "Pickup rates and initial substance concentrations have unequal lengths"
         );
 
+        assert!(
+            self.source.proto_ton.evaporation_rates.is_empty()
+                || self.source.proto_ton.evaporation_rates.len()
+                    == self.source.proto_ton.substances.len(),
+            "Evaporation rates must be either empty or have as many entries as there are substances"
+        );
+
+        if let Shape::Mesh { ref triangles, .. } = self.source.shape {
+            assert!(
+                triangles.total_weight() > 0.0,
+                "Mesh source has no triangle with non-zero area, or no vertex with positive weight"
+            );
+        }
+
+        if let Some(ref vertex_substances) = self.source.vertex_substances {
+            let triangles = match self.source.shape {
+                Shape::Mesh { ref triangles, .. } => &triangles.triangles,
+                _ => panic!("Vertex substances are only supported for mesh sources"),
+            };
+
+            let substance_count = self.source.proto_ton.pickup_rates.len();
+            let mut vertices = triangles.iter().flat_map(|t| vec![&t.0, &t.1, &t.2]);
+            assert!(
+                vertices.all(|v| vertex_substances(v).len() == substance_count),
+                "Vertex substances and pickup rates have unequal lengths"
+            );
+        }
+
         self.source
     }
 }
 
+/// Maps two numbers in `[0, 1)` to a uniformly distributed point on the disk with the
+/// given center, normal and radius.
+fn disk_point(center: Vec3, normal: Vec3, radius: f32, (u, v): (f32, f32)) -> Vec3 {
+    let (tangent, bitangent) = direction::orthonormal_basis(normal);
+    let distance = radius * u.sqrt();
+    let phi = 2.0 * PI * v;
+    center + distance * (phi.cos() * tangent + phi.sin() * bitangent)
+}
+
+/// Maps two numbers in `[0, 1)` to barycentric coordinates of a uniformly distributed point
+/// on a triangle.
+fn triangle_barycentric((u, v): (f32, f32)) -> Vec3 {
+    let sqrt_u = u.sqrt();
+    Vec3::new(1.0 - sqrt_u, sqrt_u * (1.0 - v), sqrt_u * v)
+}
+
+/// Maps two numbers in `[0, 1)` to barycentric coordinates of a point on a triangle with a
+/// density proportional to the barycentric coordinate of the vertex with the given index.
+fn vertex_weighted_barycentric(vertex: usize, (u, v): (f32, f32)) -> Vec3 {
+    // The coordinate of the vertex is Beta(2, 2) distributed, invert its CDF 3x² - 2x³
+    let weighted = 0.5 + ((1.0 - 2.0 * u).acos() / 3.0 + 4.0 * PI / 3.0).cos();
+    let weighted = weighted.max(0.0).min(1.0);
+    // The remainder is split uniformly between the other two vertices
+    let (next, prev) = ((1.0 - weighted) * v, (1.0 - weighted) * (1.0 - v));
+
+    match vertex {
+        0 => Vec3::new(weighted, next, prev),
+        1 => Vec3::new(prev, weighted, next),
+        _ => Vec3::new(next, prev, weighted),
+    }
+}
+
 #[cfg(test)]
 mod test {
     extern crate aitios_asset;
 
     use super::*;
+    use geom::Vec2;
+    use std::collections::HashSet;
 
     #[test]
     fn test_shoot_from_mesh() {
@@ -271,6 +1284,7 @@ mod test {
                  ton,
                  origin,
                  direction,
+                 ..
              }| ton.p_flow == 0.2 && origin.y > 0.1 && direction.y < 0.0
         ));
 
@@ -286,7 +1300,376 @@ mod test {
                  ton,
                  origin,
                  direction,
+                 ..
              }| ton.p_flow == 0.2 && origin.y > 0.1 && direction.y < 0.0
         ));
     }
+
+    #[test]
+    fn test_stratified_point_source() {
+        let src = TonSourceBuilder::new()
+            .emission_count(4)
+            .emission_sampling(EmissionSampling::Stratified)
+            .build();
+
+        // Two by two strata, half of them in the upper hemisphere
+        let directions: Vec<Vec3> = src.emit().map(|e| e.direction).collect();
+        assert_eq!(directions.len(), 4);
+        assert_eq!(directions.iter().filter(|d| d.z > 0.0).count(), 2);
+        assert!(directions
+            .iter()
+            .all(|d| (d.magnitude() - 1.0).abs() < 0.0001));
+
+        // Unit square split along the diagonal, strata select both halves equally often
+        let vertex = |x, z| Vertex {
+            position: Vec3::new(x, 0.0, z),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            texcoords: Vec2::new(x, z),
+        };
+        let square = vec![
+            TupleTriangle(vertex(0.0, 0.0), vertex(1.0, 1.0), vertex(1.0, 0.0)),
+            TupleTriangle(vertex(0.0, 0.0), vertex(0.0, 1.0), vertex(1.0, 1.0)),
+        ];
+        for _ in 0..10 {
+            let src = TonSourceBuilder::new()
+                .emission_count(4)
+                .emission_sampling(EmissionSampling::Stratified)
+                .triangles_shaped(square.clone(), false)
+                .build();
+
+            let origins: Vec<Vec3> = src.emit().map(|e| e.origin).collect();
+            assert_eq!(origins.len(), 4);
+            assert_eq!(origins.iter().filter(|o| o.x > o.z).count(), 2);
+            assert_eq!(origins.iter().filter(|o| o.x < o.z).count(), 2);
+        }
+    }
+
+    #[test]
+    fn test_stratified_mesh_source_emits_from_every_triangle() {
+        // Grid of unit squares, each split along the diagonal into two triangles of equal area
+        let cells = 40;
+        let vertex = |x: usize, z: usize| Vertex {
+            position: Vec3::new(x as f32, 0.0, z as f32),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            texcoords: Vec2::new(x as f32, z as f32),
+        };
+        let triangles: Vec<TupleTriangle<Vertex>> = (0..cells)
+            .flat_map(|x| (0..cells).map(move |z| (x, z)))
+            .flat_map(|(x, z)| {
+                vec![
+                    TupleTriangle(vertex(x, z), vertex(x + 1, z + 1), vertex(x + 1, z)),
+                    TupleTriangle(vertex(x, z), vertex(x, z + 1), vertex(x + 1, z + 1)),
+                ]
+            })
+            .collect();
+        let emission_count = triangles.len();
+
+        let src = TonSourceBuilder::new()
+            .emission_count(emission_count)
+            .emission_sampling(EmissionSampling::Stratified)
+            .triangles_shaped(triangles, false)
+            .build();
+
+        let emitting_triangles: HashSet<(i32, i32, bool)> = src
+            .emit()
+            .map(|e| {
+                let (cell_x, cell_z) = (e.origin.x.floor(), e.origin.z.floor());
+                let below_diagonal = e.origin.x - cell_x > e.origin.z - cell_z;
+                (cell_x as i32, cell_z as i32, below_diagonal)
+            })
+            .collect();
+
+        // A column of a grid of strata would only select about 57 different triangles
+        assert!(emitting_triangles.len() > emission_count * 9 / 10);
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_empty_mesh_source_rejected() {
+        TonSourceBuilder::new()
+            .triangles_shaped(Vec::new(), false)
+            .build();
+    }
+
+    #[test]
+    fn test_directional_covers_bounds() {
+        let src = TonSourceBuilder::new()
+            .emission_count(100)
+            .directional_shaped(Vec3::new(0.0, -2.0, 0.0))
+            .fit_to_bounds(&Aabb {
+                min: Vec3::new(-1.0, -1.0, -1.0),
+                max: Vec3::new(1.0, 1.0, 1.0),
+            })
+            .build();
+
+        // Disk above the bounding sphere, shooting down
+        let radius = 3.0_f32.sqrt();
+        assert!(src.emit().all(|e| {
+            let distance_from_axis = (e.origin.x * e.origin.x + e.origin.z * e.origin.z).sqrt();
+            e.direction == Vec3::new(0.0, -1.0, 0.0)
+                && e.origin.y > radius
+                && distance_from_axis <= radius + 0.0001
+        }));
+    }
+
+    #[test]
+    fn test_unfitted_directional_discards_emissions() {
+        let src = TonSourceBuilder::new()
+            .emission_count(10)
+            .directional_shaped(Vec3::new(0.0, -1.0, 0.0))
+            .build();
+
+        assert!(src.emit().all(|e| e.weight == 0.0 && e.ton.weight == 0.0));
+        assert_eq!(src.emit_one().weight, 0.0);
+    }
+
+    #[test]
+    fn test_dome_shoots_inward() {
+        let center = Vec3::new(1.0, 2.0, 3.0);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+
+        for &distribution in &[
+            DomeDistribution::InwardUniform,
+            DomeDistribution::InwardCosine,
+            DomeDistribution::Center,
+            // Not fitted to scene bounds yet
+            DomeDistribution::SceneBounds,
+        ] {
+            let src = TonSourceBuilder::new()
+                .emission_count(100)
+                .dome_shaped(center, 2.0, up, distribution)
+                .build();
+
+            assert!(src.emit().all(|e| {
+                let inward = (center - e.origin).normalize();
+                (e.origin - center).dot(up) >= -0.0001 && e.direction.dot(inward) >= -0.0001
+            }));
+        }
+
+        // Bounds shrunk to the center, so all emissions aim at it
+        let src = TonSourceBuilder::new()
+            .emission_count(100)
+            .dome_shaped(center, 2.0, up, DomeDistribution::SceneBounds)
+            .fit_to_bounds(&Aabb {
+                min: center,
+                max: center,
+            })
+            .build();
+        assert!(src.emit().all(|e| {
+            let towards_center = (center - e.origin).normalize();
+            (e.direction - towards_center).magnitude() < 0.0001
+        }));
+    }
+
+    #[test]
+    fn test_volume_sources_emit_inside() {
+        let min = Vec3::new(-1.0, 2.0, -3.0);
+        let max = Vec3::new(1.0, 4.0, 3.0);
+        let rain = VolumeDirections::Cone {
+            direction: Vec3::new(0.0, -1.0, 0.0),
+            half_angle: 0.1,
+        };
+
+        let src = TonSourceBuilder::new()
+            .emission_count(100)
+            .box_shaped(min, max, rain)
+            .build();
+
+        assert!(src.emit().all(|e| {
+            e.origin.x >= min.x
+                && e.origin.y >= min.y
+                && e.origin.z >= min.z
+                && e.origin.x <= max.x
+                && e.origin.y <= max.y
+                && e.origin.z <= max.z
+                && e.direction.y < -0.99
+        }));
+
+        let center = Vec3::new(1.0, 2.0, 3.0);
+        let src = TonSourceBuilder::new()
+            .emission_count(100)
+            .sphere_shaped(center, 0.5, VolumeDirections::Isotropic)
+            .build();
+
+        assert!(src.emit().all(|e| e.origin.distance(center) <= 0.5001));
+    }
+
+    #[test]
+    fn test_aim_at_region() {
+        let center = Vec3::new(0.0, 0.0, 10.0);
+        let radius = 1.0;
+
+        let src = TonSourceBuilder::new()
+            .emission_count(100)
+            .substances(&vec![1.0])
+            .pickup_rates(vec![0.0])
+            .aim_at_region(center, radius)
+            .build();
+
+        // Point sources emit in all directions, so the weight is the fraction of the
+        // sphere of directions covered by the region
+        let cos_half_angle = (1.0 - 0.01_f32).sqrt();
+        let expected_weight = 0.5 * (1.0 - cos_half_angle);
+
+        assert!(src.emit().all(|e| {
+            e.direction.dot(center.normalize()) >= cos_half_angle - 0.0001
+                && (e.weight - expected_weight).abs() < 0.0001
+                && (e.ton.weight - expected_weight).abs() < 0.0001
+                && e.ton.substances[0] == 1.0
+        }));
+    }
+
+    #[test]
+    fn test_weighted_mesh_with_vertex_substances() {
+        let entities = aitios_asset::obj::load(
+            "test-scenes/buddha-scene-ton-source-mesh/buddha-scene-ton-source-sun.obj",
+        ).unwrap();
+
+        // Only vertices on one side emit
+        let src = TonSourceBuilder::new()
+            .emission_count(100)
+            .pickup_rates(vec![0.0])
+            .substances(&vec![0.0])
+            .weighted_entity_shaped(
+                &entities[0],
+                false,
+                Arc::new(|v: &Vertex| if v.position.x > 0.0 { 1.0 } else { 0.0 }),
+            )
+            .vertex_substances(Arc::new(|_: &Vertex| vec![2.0]))
+            .build();
+
+        assert!(src.emit().all(|e| {
+            (e.ton.substances[0] - 2.0).abs() < 0.0001 && e.origin.y > 0.1 && e.direction.y < 0.0
+        }));
+
+        // Triangles entirely on the other side never emit
+        let min_x = src.emit().map(|e| e.origin.x).fold(INFINITY, f32::min);
+        let max_edge = entities[0]
+            .mesh
+            .triangles()
+            .map(|t| {
+                let (a, b, c) = t.positions();
+                a.distance(b).max(b.distance(c)).max(c.distance(a))
+            })
+            .fold(0.0, f32::max);
+        assert!(min_x > -max_edge);
+    }
+
+    #[test]
+    fn test_weighted_mesh_density() {
+        // Unit square with weights increasing along x, so the density is proportional to x
+        // and the expected x coordinate is 2/3
+        let vertex = |x, z| Vertex {
+            position: Vec3::new(x, 0.0, z),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            texcoords: Vec2::new(x, z),
+        };
+        let square = vec![
+            TupleTriangle(vertex(0.0, 0.0), vertex(1.0, 1.0), vertex(1.0, 0.0)),
+            TupleTriangle(vertex(0.0, 0.0), vertex(0.0, 1.0), vertex(1.0, 1.0)),
+        ];
+
+        for &sampling in &[EmissionSampling::Random, EmissionSampling::Stratified] {
+            let src = TonSourceBuilder::new()
+                .emission_count(10000)
+                .emission_sampling(sampling)
+                .weighted_triangles_shaped(
+                    square.clone(),
+                    false,
+                    Arc::new(|v: &Vertex| v.position.x),
+                )
+                .build();
+
+            let mean_x = src.emit().map(|e| e.origin.x).sum::<f32>() / 10000.0;
+            assert!((mean_x - 2.0 / 3.0).abs() < 0.02);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_weighted_mesh_without_positive_weights_rejected() {
+        TonSourceBuilder::new()
+            .weighted_triangles_shaped(
+                vec![unit_triangle()],
+                false,
+                Arc::new(|v: &Vertex| -v.position.x),
+            )
+            .build();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_vertex_substances_match_pickup_rates() {
+        TonSourceBuilder::new()
+            .pickup_rates(vec![0.0])
+            .substances(&vec![0.0])
+            .triangles_shaped(vec![unit_triangle()], false)
+            .vertex_substances(Arc::new(|_: &Vertex| vec![2.0, 1.0]))
+            .build();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_vertex_substances_checked_on_every_vertex() {
+        // Only the vertex off the origin has the wrong amount of substances
+        TonSourceBuilder::new()
+            .pickup_rates(vec![0.0])
+            .substances(&vec![0.0])
+            .triangles_shaped(vec![unit_triangle()], false)
+            .vertex_substances(Arc::new(|v: &Vertex| {
+                if v.position.x > 0.5 {
+                    vec![2.0, 1.0]
+                } else {
+                    vec![2.0]
+                }
+            }))
+            .build();
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_vertex_substances_need_mesh() {
+        TonSourceBuilder::new()
+            .pickup_rates(vec![0.0])
+            .substances(&vec![0.0])
+            .point_shaped(0.0, 0.0, 0.0)
+            .vertex_substances(Arc::new(|_: &Vertex| vec![2.0]))
+            .build();
+    }
+
+    fn unit_triangle() -> TupleTriangle<Vertex> {
+        let vertex = |x, z| Vertex {
+            position: Vec3::new(x, 0.0, z),
+            normal: Vec3::new(0.0, 1.0, 0.0),
+            texcoords: Vec2::new(x, z),
+        };
+        TupleTriangle(vertex(0.0, 0.0), vertex(0.0, 1.0), vertex(1.0, 0.0))
+    }
+
+    #[test]
+    fn test_parameter_distributions() {
+        let src = TonSourceBuilder::new()
+            .emission_count(100)
+            .pickup_rates(vec![0.0])
+            .substances(&vec![2.0])
+            .p_flow_distribution(Distribution::Uniform { min: 0.2, max: 0.4 })
+            .interaction_radius_distribution(Distribution::Normal {
+                mean: 0.1,
+                std_dev: 0.05,
+                min: 0.05,
+                max: 0.15,
+            })
+            .substance_load_distribution(Distribution::Choice(vec![0.5, 1.0]))
+            .build();
+
+        assert!(src.emit().all(|e| {
+            let ton = e.ton;
+            ton.p_flow >= 0.2
+                && ton.p_flow <= 0.4
+                && ton.interaction_radius >= 0.05
+                && ton.interaction_radius <= 0.15
+                && ((ton.substances[0] - 1.0).abs() < 0.0001
+                    || (ton.substances[0] - 2.0).abs() < 0.0001)
+        }));
+    }
 }
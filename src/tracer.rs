@@ -1,9 +1,11 @@
+use config::{FlowModel, Integration, Tracing, Wind};
 use geom::prelude::*;
-use geom::{TupleTriangle, Vec3, Vertex};
-use spatial::Octree;
+use geom::{Aabb, TupleTriangle, Vec3, Vertex};
+use geometry::{barycentric, Geometry, Intersection, TriangleGeometry};
+use rayon::prelude::*;
 #[cfg(feature = "debug_tracing")]
 use std::cell::RefCell;
-use std::f32::INFINITY;
+use std::f32::{EPSILON, INFINITY, NEG_INFINITY};
 
 #[cfg(feature = "debug_tracing")]
 enum TracingEvent {
@@ -16,52 +18,417 @@ const MAX_TRACING_EVENT_COUNT: usize = 600;
 /// Delta to move before checking for intersections. This avoids rays
 /// intersecting the triangle they originated from due to floating point
 /// imprecision.
-const SELF_INTERSECTION_EPSILON: f32 = 0.0001;
-const FLOW_ADHESIVENESS: f32 = 1.1; // Allow downward motion of flow to be 10% longer than to be expected on a flat surface, so it can flow upward a little bit
-
-pub struct Tracer {
-    geometry: Octree<TupleTriangle<Vertex>>,
-    gravity_direction: Vec3,
+pub(crate) const SELF_INTERSECTION_EPSILON: f32 = 0.0001;
+/// Ray parameter that straight rays are advanced by before checking for intersections,
+/// for the same reason. Relative to the direction, which is not necessarily normalized.
+const STRAIGHT_OFFSET: f32 = 0.0000001;
+/// Tolerance for barycentric coordinates and parabola parameters when
+/// refining intersections analytically.
+const ANALYTIC_TOLERANCE: f32 = 0.00001;
+/// Number of coherent queries traced together in batched tracing.
+const BATCH_SIZE: usize = 64;
+/// Bits per axis that origins are quantized to when sorting batched queries.
+const MORTON_BITS: u32 = 10;
+
+/// Moves tons through the scene, finding intersections with the given geometry.
+pub struct Tracer<G = TriangleGeometry> {
+    geometry: G,
+    config: Tracing,
     #[cfg(feature = "debug_tracing")]
     first_tracing_events: RefCell<Vec<TracingEvent>>,
 }
 
 #[derive(Debug)]
-pub struct Hit<'a> {
+pub struct Hit {
     pub intersection_point: Vec3,
     pub incoming_direction: Vec3,
-    pub triangle: &'a TupleTriangle<Vertex>,
+    /// Index of the hit triangle or other element in the geometry, see `Tracer::triangle`.
+    /// For the default geometry, this is the index in the order the triangles were passed
+    /// to the tracer.
+    pub triangle_idx: usize,
+    /// Index of the entity the hit triangle belongs to, `None` if the tracer was
+    /// constructed without entities or a primitive was hit.
+    pub entity_idx: Option<usize>,
+    /// `false` if the hit element has no surfels, e.g. an analytic primitive, so tons
+    /// hitting it do not exchange substances.
+    pub has_surfels: bool,
+    /// Barycentric coordinates of the intersection point on the triangle, `None` if no
+    /// triangle was hit.
+    pub barycentric: Option<Vec3>,
+    /// Normalized geometric normal at the intersection point, which is the face normal
+    /// for triangles.
+    pub normal: Vec3,
+    /// `true` if the triangle or primitive was hit from the side its normal points to,
+    /// `false` for hits from behind.
+    pub front_face: bool,
+    /// Length of the path travelled from the origin of the trace to the
+    /// intersection point, summed over all segments of the trace.
+    pub distance: f32,
+    /// Speed at impact for parabolic traces. Straight and flow traces do not model
+    /// speed and report zero.
+    pub speed: f32,
+}
+
+/// A flowing ton to move over the surface in batched tracing, see `Tracer::trace_flow`.
+#[derive(Debug, Clone, Copy)]
+pub struct FlowQuery {
+    pub from: Vec3,
+    pub up: Vec3,
+    pub tangential_direction: Vec3,
+    pub flow_distance: f32,
+}
+
+/// Outcome of moving a flowing ton over the surface once.
+#[derive(Debug)]
+pub enum FlowStep {
+    /// The ton is back on the surface, or hit a wall in a concave neighbourhood.
+    Attached(Hit),
+    /// The ton lost contact with the surface, e.g. by flowing over a convex edge.
+    Detached {
+        /// Position in the air where the ton was found to have left the surface.
+        point: Vec3,
+        /// Length of the path travelled until the ton was found to have left the surface.
+        distance: f32,
+    },
+}
+
+/// Number of segments that flowing tons search for the surface along in a flow step.
+const FLOW_SEGMENT_COUNT: usize = 2;
+
+/// Segments that a flowing ton searches for the surface along, in order, and where it
+/// lost contact with the surface if none of them hits.
+struct FlowSearch {
+    segments: [FlowSegment; FLOW_SEGMENT_COUNT],
+    detached_point: Vec3,
+    detached_distance: f32,
+}
+
+/// Line segment along a normalized direction, with the length of the path travelled
+/// before its origin.
+struct FlowSegment {
+    origin: Vec3,
+    direction: Vec3,
+    length: f32,
+    distance_before: f32,
+}
+
+impl FlowSearch {
+    fn detached(&self) -> FlowStep {
+        FlowStep::Detached {
+            point: self.detached_point,
+            distance: self.detached_distance,
+        }
+    }
 }
 
-impl Tracer {
+impl FlowSegment {
+    fn end(&self) -> Vec3 {
+        self.origin + self.direction * self.length
+    }
+
+    /// Gets origin, direction and length to query the geometry with.
+    fn query(&self) -> (Vec3, Vec3, f32) {
+        (self.origin, self.direction, self.length)
+    }
+}
+
+impl Tracer<TriangleGeometry> {
     pub fn new<I>(triangles: I) -> Self
     where
         I: IntoIterator<Item = TupleTriangle<Vertex>>,
     {
+        Self::new_with_config(triangles, &Default::default())
+    }
+
+    pub fn new_with_config<I>(triangles: I, config: &Tracing) -> Self
+    where
+        I: IntoIterator<Item = TupleTriangle<Vertex>>,
+    {
+        let geometry = TriangleGeometry::new(triangles, config.acceleration);
+        Self::with_geometry(geometry, config)
+    }
+
+    /// Creates a tracer for triangles paired with the index of the entity they belong to,
+    /// so hits report entity indexes.
+    pub fn new_with_entities<I>(triangles: I, config: &Tracing) -> Self
+    where
+        I: IntoIterator<Item = (usize, TupleTriangle<Vertex>)>,
+    {
+        let geometry = TriangleGeometry::new_with_entities(triangles, config.acceleration);
+        Self::with_geometry(geometry, config)
+    }
+}
+
+impl<G: Geometry> Tracer<G> {
+    /// Creates a tracer for custom geometry. The acceleration setting of the configuration
+    /// is ignored, since the geometry brings its own.
+    ///
+    /// Panics if the gravity direction of the configuration is zero, the gravity magnitude
+    /// is not positive or a wind profile has no positive reference height.
+    pub fn with_geometry(geometry: G, config: &Tracing) -> Self {
+        assert!(
+            config.gravity_direction.magnitude2() > 0.0,
+            "Gravity direction must not be zero"
+        );
+        assert!(
+            config.gravity_magnitude > 0.0,
+            "Gravity magnitude must be positive"
+        );
+        if let Wind::Profile {
+            reference_height, ..
+        } = config.wind
+        {
+            assert!(
+                reference_height > 0.0,
+                "Reference height of wind profiles must be positive"
+            );
+        }
+
+        let mut config = config.clone();
+        config.gravity_direction = config.gravity_direction.normalize();
+
         Tracer {
-            geometry: triangles.into_iter().collect(),
-            gravity_direction: Vec3::new(0.0, -1.0, 0.0),
+            geometry,
+            config,
             #[cfg(feature = "debug_tracing")]
             first_tracing_events: RefCell::new(Vec::new()),
         }
     }
 
     pub fn trace_straight(&self, from: Vec3, direction: Vec3) -> Option<Hit> {
-        let from = from + direction * 0.0000001; // avoid self-intersection
+        let from = straight_origin(from, direction);
         self.geometry
-            .ray_intersection_target_and_parameter(from, direction)
-            .map(|(hit_tri, t)| {
-                let intersection_point = from + t * direction;
+            .ray_intersection(from, direction)
+            .map(|intersection| self.straight_hit(from, direction, intersection))
+    }
 
-                #[cfg(feature = "debug_tracing")]
-                self.debug_straight(from, intersection_point);
+    /// Traces many straight rays, given as origin and direction, and returns their hits in
+    /// the order of the rays, like calling `trace_straight` for each of them.
+    ///
+    /// Rays are sorted into groups with similar directions and nearby origins, which are
+    /// traced in parallel and passed to the geometry together.
+    pub fn trace_straight_batch(&self, rays: &[(Vec3, Vec3)]) -> Vec<Option<Hit>> {
+        self.coherent_batches(
+            rays,
+            |&ray| ray,
+            |group| {
+                let offset_rays: Vec<(Vec3, Vec3)> = group
+                    .iter()
+                    .map(|&&(from, direction)| (straight_origin(from, direction), direction))
+                    .collect();
+
+                batch_intersections(self.geometry.ray_intersections(&offset_rays), &offset_rays)
+                    .into_iter()
+                    .zip(offset_rays.iter())
+                    .map(|(intersection, &(from, direction))| {
+                        intersection
+                            .map(|intersection| self.straight_hit(from, direction, intersection))
+                    })
+                    .collect()
+            },
+        )
+    }
 
-                Hit {
-                    intersection_point,
-                    incoming_direction: direction,
-                    triangle: hit_tri,
+    /// Creates a hit from an intersection with the straight ray from the given origin,
+    /// which was already offset.
+    fn straight_hit(&self, from: Vec3, direction: Vec3, intersection: Intersection) -> Hit {
+        let intersection_point = from + intersection.t * direction;
+
+        #[cfg(feature = "debug_tracing")]
+        self.debug_straight(from, intersection_point);
+
+        // direction is not necessarily normalized
+        let distance = intersection.t * direction.magnitude();
+        self.hit(intersection, intersection_point, direction, distance, 0.0)
+    }
+
+    /// Moves many flowing tons over the surface and returns their hits in the order of the
+    /// queries, like calling `trace_flow` for each of them.
+    ///
+    /// Queries are sorted into groups with similar directions and nearby origins, which are
+    /// traced in parallel. The segments that the tons of a group search for the surface
+    /// along, and the rays of the tons falling off it, are passed to the geometry together.
+    pub fn trace_flow_batch(&self, queries: &[FlowQuery]) -> Vec<Option<Hit>> {
+        self.coherent_batches(
+            queries,
+            |q| (q.from, q.tangential_direction),
+            |group| {
+                let steps = self.flow_step_group(group);
+
+                // Tons that lost contact with the surface follow gravity
+                let gravity_direction = self.config.gravity_direction;
+                let falls: Vec<(Vec3, Vec3)> = steps
+                    .iter()
+                    .filter_map(|step| match step {
+                        &FlowStep::Detached { point, .. } => Some((point, gravity_direction)),
+                        &FlowStep::Attached(_) => None,
+                    })
+                    .collect();
+                let mut fall_intersections =
+                    batch_intersections(self.geometry.ray_intersections(&falls), &falls)
+                        .into_iter();
+
+                steps
+                    .into_iter()
+                    .map(|step| match step {
+                        FlowStep::Attached(hit) => Some(hit),
+                        FlowStep::Detached { point, distance } => fall_intersections
+                            .next()
+                            .unwrap()
+                            .map(|intersection| self.fall_hit(point, distance, intersection)),
+                    })
+                    .collect()
+            },
+        )
+    }
+
+    /// Moves many flowing tons over the surface once and returns the outcomes in the order
+    /// of the queries, like calling `flow_step` for each of them, but in coherent groups
+    /// like `trace_flow_batch`.
+    pub fn flow_step_batch(&self, queries: &[FlowQuery]) -> Vec<FlowStep> {
+        self.coherent_batches(
+            queries,
+            |q| (q.from, q.tangential_direction),
+            |group| self.flow_step_group(group),
+        )
+    }
+
+    /// Moves a group of flowing tons over the surface once, passing the segments that the
+    /// tons search for the surface along to the geometry together, one segment per ton at
+    /// a time.
+    fn flow_step_group(&self, group: &[&FlowQuery]) -> Vec<FlowStep> {
+        let searches: Vec<FlowSearch> = group
+            .iter()
+            .map(|q| self.flow_search(q.from, q.up, q.tangential_direction, q.flow_distance))
+            .collect();
+        let mut steps: Vec<Option<FlowStep>> = searches.iter().map(|_| None).collect();
+
+        for segment_idx in 0..FLOW_SEGMENT_COUNT {
+            // Only tons that are not back on the surface yet search on
+            let searching: Vec<usize> = (0..searches.len())
+                .filter(|&idx| steps[idx].is_none())
+                .collect();
+            let segments: Vec<(Vec3, Vec3, f32)> = searching
+                .iter()
+                .map(|&idx| searches[idx].segments[segment_idx].query())
+                .collect();
+
+            let intersections = batch_intersections(
+                self.geometry.line_segment_intersections(&segments),
+                &segments,
+            );
+            for (&idx, intersection) in searching.iter().zip(intersections) {
+                if let Some(intersection) = intersection {
+                    let segment = &searches[idx].segments[segment_idx];
+                    steps[idx] = Some(FlowStep::Attached(self.segment_hit(segment, intersection)));
                 }
+            }
+        }
+
+        steps
+            .into_iter()
+            .zip(searches)
+            .map(|(step, search)| step.unwrap_or_else(|| search.detached()))
+            .collect()
+    }
+
+    /// Sorts the queries by the octant of their direction and the position of their origin
+    /// along a Morton curve, traces groups of neighbouring queries in parallel and returns
+    /// the results in the original order of the queries.
+    fn coherent_batches<Q, R, K, T>(
+        &self,
+        queries: &[Q],
+        origin_and_direction: K,
+        trace: T,
+    ) -> Vec<R>
+    where
+        Q: Sync,
+        R: Send,
+        K: Fn(&Q) -> (Vec3, Vec3) + Sync,
+        T: Fn(&[&Q]) -> Vec<R> + Sync,
+    {
+        let bounds = self.geometry.bounds();
+        let mut order: Vec<(u64, usize)> = queries
+            .par_iter()
+            .enumerate()
+            .map(|(idx, query)| {
+                let (origin, direction) = origin_and_direction(query);
+                (coherence_key(&bounds, origin, direction), idx)
             })
+            .collect();
+        order.par_sort_unstable();
+
+        let traced: Vec<(usize, R)> = order
+            .par_chunks(BATCH_SIZE)
+            .flat_map(|chunk| {
+                let group: Vec<&Q> = chunk.iter().map(|&(_, idx)| &queries[idx]).collect();
+                let results = trace(&group);
+                assert_eq!(
+                    results.len(),
+                    group.len(),
+                    "Batched tracing returned a different number of results than queries"
+                );
+                chunk
+                    .iter()
+                    .map(|&(_, idx)| idx)
+                    .zip(results)
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+
+        let mut results: Vec<Option<R>> = (0..queries.len()).map(|_| None).collect();
+        for (idx, result) in traced {
+            results[idx] = Some(result);
+        }
+        results.into_iter().map(|r| r.unwrap()).collect()
+    }
+
+    /// Gets the triangle with the given index, as reported in hits, or `None` if the
+    /// geometry does not consist of triangles.
+    pub fn triangle(&self, triangle_idx: usize) -> Option<&TupleTriangle<Vertex>> {
+        self.geometry.triangle(triangle_idx)
+    }
+
+    /// Whether hits report the entity of the element that was hit.
+    pub fn has_entities(&self) -> bool {
+        self.geometry.has_entities()
+    }
+
+    /// Creates a hit from the given intersection with the geometry.
+    fn hit(
+        &self,
+        intersection: Intersection,
+        intersection_point: Vec3,
+        incoming_direction: Vec3,
+        distance: f32,
+        speed: f32,
+    ) -> Hit {
+        let normal = intersection.normal;
+
+        Hit {
+            intersection_point,
+            incoming_direction,
+            triangle_idx: intersection.idx,
+            entity_idx: self.geometry.entity_idx(intersection.idx),
+            has_surfels: self.geometry.has_surfels(intersection.idx),
+            barycentric: intersection.barycentric,
+            normal,
+            front_face: is_front_face(normal, incoming_direction),
+            distance,
+            speed,
+        }
+    }
+
+    /// Gets the normalized direction that gravity pulls towards.
+    pub fn gravity_direction(&self) -> Vec3 {
+        self.config.gravity_direction
+    }
+
+    /// Gets the velocity of the wind at the given position.
+    pub fn wind_at(&self, position: Vec3) -> Vec3 {
+        self.config
+            .wind
+            .velocity_at(position, -self.config.gravity_direction)
     }
 
     /// `upward_parabola_height` indicates maximum height of a bounce in the special case it is flung
@@ -73,46 +440,110 @@ impl Tracer {
         direction: Vec3,
         upward_parabola_height: f32,
     ) -> Option<Hit> {
-        let gravity_mag = 9.81_f32;
-        let timestep = 1.0 / 60.0; // 0.0333333 seconds, more is more exact but slower
+        self.trace_parabolic_with_drag(from, direction, upward_parabola_height, 0.0)
+    }
 
-        let gravity_acceleration = gravity_mag * self.gravity_direction;
+    /// Like `trace_parabolic`, but drags the ton along with the wind.
+    ///
+    /// `drag` is a linear drag coefficient in 1/s, accelerating the ton towards the wind velocity.
+    /// Without drag, wind has no effect on the trajectory.
+    pub fn trace_parabolic_with_drag(
+        &self,
+        from: Vec3,
+        direction: Vec3,
+        upward_parabola_height: f32,
+        drag: f32,
+    ) -> Option<Hit> {
+        let gravity_mag = self.config.gravity_magnitude;
         let takeoff_velocity_mag = (2.0 * gravity_mag * upward_parabola_height).sqrt();
-        let mut velocity = takeoff_velocity_mag * direction;
-        let mut position = from + direction * SELF_INTERSECTION_EPSILON;
+        let position = from + direction * SELF_INTERSECTION_EPSILON;
+        self.trace_ballistic_from(position, takeoff_velocity_mag * direction, drag)
+    }
 
-        let scene_bounds = {
-            let mut bounds = self.geometry.bounds();
-            bounds.max.y = INFINITY; // ignore if out of bounds in positive y direction since gravity will eventually pull it downward
-            bounds
+    /// Traces a ton thrown from the given point with the given velocity under gravity,
+    /// e.g. from a source, which may also lie outside the scene bounds.
+    ///
+    /// `drag` is a linear drag coefficient in 1/s, accelerating the ton towards the wind velocity.
+    pub fn trace_ballistic(&self, from: Vec3, velocity: Vec3, drag: f32) -> Option<Hit> {
+        let speed = velocity.magnitude();
+        let direction = if speed > 0.0 {
+            velocity / speed
+        } else {
+            self.config.gravity_direction
         };
 
-        // REVIEW This should take into account that the source could be outside the scene bounds.
-        //        At emission time, the tracing will be always straight though, so it should not come to that
-        while scene_bounds.is_point_inside(position) {
-            velocity += gravity_acceleration * timestep;
+        self.trace_ballistic_from(from + direction * SELF_INTERSECTION_EPSILON, velocity, drag)
+    }
+
+    fn trace_ballistic_from(&self, position: Vec3, velocity: Vec3, drag: f32) -> Option<Hit> {
+        let gravity_acceleration = self.config.gravity_magnitude * self.config.gravity_direction;
+        let scene_bounds = self.parabolic_bounds(position);
+
+        match self.config.integration {
+            Integration::Analytic { timestep } if drag == 0.0 => self.trace_parabolic_analytic(
+                position,
+                velocity,
+                gravity_acceleration,
+                timestep,
+                &scene_bounds,
+            ),
+            Integration::Analytic { timestep } | Integration::Euler { timestep } => self
+                .trace_parabolic_euler(
+                    position,
+                    velocity,
+                    gravity_acceleration,
+                    drag,
+                    timestep,
+                    &scene_bounds,
+                ),
+        }
+    }
+
+    fn trace_parabolic_euler(
+        &self,
+        mut position: Vec3,
+        mut velocity: Vec3,
+        gravity_acceleration: Vec3,
+        drag: f32,
+        timestep: f32,
+        scene_bounds: &Aabb,
+    ) -> Option<Hit> {
+        let mut distance = SELF_INTERSECTION_EPSILON;
+        let mut time = 0.0;
+
+        while scene_bounds.is_point_inside(position) && time < self.config.max_flight_time {
+            time += timestep;
+            let acceleration = if drag == 0.0 {
+                gravity_acceleration
+            } else {
+                gravity_acceleration + drag * (self.wind_at(position) - velocity)
+            };
+            velocity += acceleration * timestep;
 
             let spatial_delta = velocity * timestep;
             let dist = spatial_delta.magnitude();
             let direction = spatial_delta / dist;
 
-            if let Some((hit_tri, t)) = self
+            if let Some(intersection) = self
                 .geometry
-                .line_segment_intersection_target_and_parameter(position, direction, dist)
+                .line_segment_intersection(position, direction, dist)
             {
-                let intersection_point = position + t * direction;
+                let intersection_point = position + intersection.t * direction;
 
                 #[cfg(feature = "debug_tracing")]
                 self.debug_parabolic(position, intersection_point);
 
-                return Some(Hit {
+                return Some(self.hit(
+                    intersection,
                     intersection_point,
-                    incoming_direction: direction,
-                    triangle: hit_tri,
-                });
+                    direction,
+                    distance + intersection.t,
+                    velocity.magnitude(),
+                ));
             } else {
                 // No intersection, safe to move particle without penetrating objects
                 position += spatial_delta;
+                distance += dist;
 
                 #[cfg(feature = "debug_tracing")]
                 self.debug_parabolic(position - spatial_delta, position);
@@ -122,6 +553,125 @@ impl Tracer {
         None
     }
 
+    fn trace_parabolic_analytic(
+        &self,
+        start: Vec3,
+        takeoff_velocity: Vec3,
+        gravity_acceleration: Vec3,
+        timestep: f32,
+        scene_bounds: &Aabb,
+    ) -> Option<Hit> {
+        let mut position = start;
+        let mut distance = SELF_INTERSECTION_EPSILON;
+        let mut step = 0;
+
+        while scene_bounds.is_point_inside(position)
+            && (step as f32 * timestep) < self.config.max_flight_time
+        {
+            // Calculate time from step count rather than summing up timesteps
+            // so no error accumulates
+            let segment_start_time = step as f32 * timestep;
+            let segment_end_time = (step + 1) as f32 * timestep;
+            let next_position = start
+                + takeoff_velocity * segment_end_time
+                + 0.5 * gravity_acceleration * segment_end_time * segment_end_time;
+
+            let spatial_delta = next_position - position;
+            let dist = spatial_delta.magnitude();
+            let direction = spatial_delta / dist;
+
+            if let Some(mut intersection) = self
+                .geometry
+                .line_segment_intersection(position, direction, dist)
+            {
+                // The segment is only an approximation, intersect the hit triangle
+                // with the exact parabola. If the exact intersection misses the
+                // triangle, which can happen close to edges, use the segment hit.
+                let exact = match self.geometry.triangle(intersection.idx) {
+                    Some(triangle) => parabola_triangle_intersection(
+                        start,
+                        takeoff_velocity,
+                        gravity_acceleration,
+                        triangle,
+                        segment_start_time,
+                        segment_end_time,
+                    )
+                    .map(|(point, velocity)| {
+                        intersection.barycentric = Some(barycentric(triangle, point));
+                        (point, velocity)
+                    }),
+                    // Primitives and other elements are intersected with the segment only
+                    None => None,
+                };
+                let (intersection_point, velocity) = exact.unwrap_or_else(|| {
+                    (
+                        position + intersection.t * direction,
+                        takeoff_velocity + gravity_acceleration * segment_end_time,
+                    )
+                });
+                let speed = velocity.magnitude();
+
+                #[cfg(feature = "debug_tracing")]
+                self.debug_parabolic(position, intersection_point);
+
+                return Some(self.hit(
+                    intersection,
+                    intersection_point,
+                    velocity / speed,
+                    distance + intersection_point.distance(position),
+                    speed,
+                ));
+            }
+
+            #[cfg(feature = "debug_tracing")]
+            self.debug_parabolic(position, next_position);
+
+            position = next_position;
+            distance += dist;
+            step += 1;
+        }
+
+        None
+    }
+
+    /// Gets the bounds of the scene geometry, not including unbounded parts of it, e.g.
+    /// infinite planes.
+    pub fn bounds(&self) -> Aabb {
+        self.geometry.bounds()
+    }
+
+    /// Gets the scene bounds, extended to infinity against the direction of gravity,
+    /// since gravity will eventually pull tons out there back into the scene, and
+    /// extended to the given start position, e.g. of a source outside the scene.
+    ///
+    /// With unbounded geometry, the bounds are unbounded too, and parabolic tracing ends
+    /// when hitting something or after the maximum flight time.
+    fn parabolic_bounds(&self, start: Vec3) -> Aabb {
+        let mut bounds = self.bounds();
+        let gravity = self.config.gravity_direction;
+
+        if !self.geometry.is_bounded() {
+            for axis in 0..3 {
+                bounds.min[axis] = NEG_INFINITY;
+                bounds.max[axis] = INFINITY;
+            }
+            return bounds;
+        }
+
+        for axis in 0..3 {
+            bounds.min[axis] = bounds.min[axis].min(start[axis]);
+            bounds.max[axis] = bounds.max[axis].max(start[axis]);
+
+            if gravity[axis] < 0.0 {
+                bounds.max[axis] = INFINITY;
+            } else if gravity[axis] > 0.0 {
+                bounds.min[axis] = NEG_INFINITY;
+            }
+        }
+
+        bounds
+    }
+
     /// From should be on a triangle, direction should be aligned with tangential plane of triangle
     /// Up is in normal direction.
     /// flow_distance is offset in tangential direction before interacting again.
@@ -132,13 +682,71 @@ impl Tracer {
         tangential_direction: Vec3,
         flow_distance: f32,
     ) -> Option<Hit> {
-        // Upward epsilon is chosen with a fixed angle
-        let upward_epsilon = flow_distance * 1.3;
+        match self.flow_step(from, up, tangential_direction, flow_distance) {
+            FlowStep::Attached(hit) => Some(hit),
+            // Surface must have convex local neighbourhood. Follow gravity.
+            FlowStep::Detached { point, distance } => self.trace_flow_fall(point, distance),
+        }
+    }
 
-        // FIXME
-        // in the thesis it sounded better to first do
-        // tangential, and the second segment either straight
-        // down or gravity
+    /// Like `trace_flow`, but instead of following gravity when the ton loses contact with
+    /// the surface, reports the point where it lost contact.
+    pub fn flow_step(
+        &self,
+        from: Vec3,
+        up: Vec3,
+        tangential_direction: Vec3,
+        flow_distance: f32,
+    ) -> FlowStep {
+        let search = self.flow_search(from, up, tangential_direction, flow_distance);
+
+        for segment in search.segments.iter() {
+            let intersection = self.geometry.line_segment_intersection(
+                segment.origin,
+                segment.direction,
+                segment.length,
+            );
+
+            if let Some(intersection) = intersection {
+                return FlowStep::Attached(self.segment_hit(segment, intersection));
+            }
+
+            #[cfg(feature = "debug_tracing")]
+            self.debug_flow(segment.origin, segment.end());
+        }
+
+        // Not back on the surface yet
+        search.detached()
+    }
+
+    /// Gets the segments that a flowing ton searches for the surface along, according to
+    /// the flow model.
+    fn flow_search(
+        &self,
+        from: Vec3,
+        up: Vec3,
+        tangential_direction: Vec3,
+        flow_distance: f32,
+    ) -> FlowSearch {
+        match self.config.flow_model {
+            FlowModel::Diagonal => {
+                self.flow_search_diagonal(from, up, tangential_direction, flow_distance)
+            }
+            FlowModel::TangentialThenGravity => {
+                self.flow_search_tangential(from, up, tangential_direction, flow_distance)
+            }
+        }
+    }
+
+    fn flow_search_diagonal(
+        &self,
+        from: Vec3,
+        up: Vec3,
+        tangential_direction: Vec3,
+        flow_distance: f32,
+    ) -> FlowSearch {
+        // Upward epsilon is chosen with a fixed angle
+        let upward_epsilon = flow_distance * self.config.flow_lift;
 
         // First a little bias to avoid self-intersection
         let from = from + SELF_INTERSECTION_EPSILON * up;
@@ -151,25 +759,15 @@ impl Tracer {
         // provides an origin for the downward raycast.
         // If it does intersect something, we are in some sort
         // of cavity. Count as flow target even though not tangential.
-        let upward_hit = self
-            .geometry
-            .line_segment_intersection_target_and_parameter(from, up, upward_epsilon);
-
-        if let Some((hit_tri, t)) = upward_hit {
-            let intersection_point = from + t * up;
-
-            #[cfg(feature = "debug_tracing")]
-            self.debug_flow(from, intersection_point);
-
-            return Some(Hit {
-                intersection_point,
-                incoming_direction: up,
-                triangle: hit_tri,
-            });
-        }
+        let upward = FlowSegment {
+            origin: from,
+            direction: up,
+            length: upward_epsilon,
+            distance_before: SELF_INTERSECTION_EPSILON,
+        };
 
         // Now move from above to projected intersection location
-        let atop = from + upward_epsilon * up;
+        let atop = upward.end();
         let dir = (to - atop).normalize();
         // On a flat surface, the ray should intersect
         // again in sqrt(expected_dist_sqr) distance from
@@ -177,54 +775,99 @@ impl Tracer {
         // TODO cache this, sqrt is expensive
         let expected_dist_sqr = upward_epsilon * upward_epsilon + flow_distance * flow_distance;
         let expected_dist = expected_dist_sqr.sqrt();
+        let diagonal = FlowSegment {
+            origin: atop,
+            direction: dir,
+            length: expected_dist + self.config.flow_adhesiveness,
+            distance_before: SELF_INTERSECTION_EPSILON + upward_epsilon,
+        };
 
-        #[cfg(feature = "debug_tracing")]
-        self.debug_flow(from, atop);
-        let tangential_hit = self
-            .geometry
-            .line_segment_intersection_target_and_parameter(
-                atop,
-                dir,
-                expected_dist + FLOW_ADHESIVENESS,
-            );
+        FlowSearch {
+            // Lost contact at the end of the diagonal
+            detached_point: diagonal.end(),
+            detached_distance: diagonal.distance_before + diagonal.length,
+            segments: [upward, diagonal],
+        }
+    }
 
-        if let Some((hit_tri, t)) = tangential_hit {
-            let intersection_point = atop + t * dir;
+    /// Flow as described in the thesis: first move tangentially, then drop back onto the
+    /// surface against the normal, and follow gravity if the surface is not found.
+    fn flow_search_tangential(
+        &self,
+        from: Vec3,
+        up: Vec3,
+        tangential_direction: Vec3,
+        flow_distance: f32,
+    ) -> FlowSearch {
+        // First a little bias to avoid self-intersection
+        let from = from + SELF_INTERSECTION_EPSILON * up;
 
-            #[cfg(feature = "debug_tracing")]
-            self.debug_flow(atop, intersection_point);
+        // Tangential motion only hits something in concave neighbourhoods,
+        // e.g. at the bottom of a wall. Count the wall as flow target.
+        let tangential = FlowSegment {
+            origin: from,
+            direction: tangential_direction,
+            length: flow_distance,
+            distance_before: SELF_INTERSECTION_EPSILON,
+        };
 
-            return Some(Hit {
-                intersection_point,
-                incoming_direction: dir,
-                triangle: hit_tri,
-            });
-        }
+        // Then drop back onto surfaces that curve away by the same angle that
+        // the diagonal model lifts tons by, extended by the adhesiveness
+        let to = tangential.end();
+        let drop = FlowSegment {
+            origin: to,
+            direction: -up,
+            length: flow_distance * self.config.flow_lift + self.config.flow_adhesiveness,
+            distance_before: SELF_INTERSECTION_EPSILON + flow_distance,
+        };
 
-        #[cfg(feature = "debug_tracing")]
-        self.debug_flow(atop, to);
+        FlowSearch {
+            // Lost contact where the drop started
+            detached_point: to,
+            detached_distance: drop.distance_before,
+            segments: [tangential, drop],
+        }
+    }
 
-        // Not back on the surface yet, surface must have
-        // convex local neighbourhood. Follow gravity.
-        let from = atop + dir * (expected_dist + FLOW_ADHESIVENESS);
-        let secondary_target = self
-            .geometry
-            .ray_intersection_target_and_parameter(from, self.gravity_direction);
+    /// Creates a hit from an intersection with a segment searched by a flowing ton.
+    fn segment_hit(&self, segment: &FlowSegment, intersection: Intersection) -> Hit {
+        let intersection_point = segment.origin + intersection.t * segment.direction;
 
-        if let Some((hit_tri, t)) = secondary_target {
-            let intersection_point = from + self.gravity_direction * t;
+        #[cfg(feature = "debug_tracing")]
+        self.debug_flow(segment.origin, intersection_point);
+
+        self.hit(
+            intersection,
+            intersection_point,
+            segment.direction,
+            segment.distance_before + intersection.t,
+            0.0,
+        )
+    }
 
-            #[cfg(feature = "debug_tracing")]
-            self.debug_flow(from, intersection_point);
+    /// Lets a ton that lost contact with the surface while flowing fall along gravity.
+    fn trace_flow_fall(&self, from: Vec3, travelled_distance: f32) -> Option<Hit> {
+        self.geometry
+            .ray_intersection(from, self.config.gravity_direction)
+            .map(|intersection| self.fall_hit(from, travelled_distance, intersection))
+    }
 
-            return Some(Hit {
-                intersection_point,
-                incoming_direction: self.gravity_direction,
-                triangle: hit_tri,
-            });
-        }
+    /// Creates a hit from an intersection with the path of a ton falling along gravity
+    /// from the given point.
+    fn fall_hit(&self, from: Vec3, travelled_distance: f32, intersection: Intersection) -> Hit {
+        let gravity_direction = self.config.gravity_direction;
+        let intersection_point = from + gravity_direction * intersection.t;
 
-        None
+        #[cfg(feature = "debug_tracing")]
+        self.debug_flow(from, intersection_point);
+
+        self.hit(
+            intersection,
+            intersection_point,
+            gravity_direction,
+            travelled_distance + intersection.t,
+            0.0,
+        )
     }
 
     #[cfg(feature = "debug_tracing")]
@@ -328,13 +971,136 @@ impl Tracer {
     }
 }
 
+/// Intersects the exact parabola `start + velocity * t + 0.5 * acceleration * t²` with
+/// the given triangle for a time `t` between the given bounds.
+///
+/// Returns the intersection point and the velocity at that point.
+fn parabola_triangle_intersection(
+    start: Vec3,
+    velocity: Vec3,
+    acceleration: Vec3,
+    triangle: &TupleTriangle<Vertex>,
+    min_time: f32,
+    max_time: f32,
+) -> Option<(Vec3, Vec3)> {
+    let normal = triangle.normal();
+    let (vertex_a, _, _) = triangle.positions();
+
+    // Signed distance to the plane of the triangle is quadratic in time
+    let quadratic = 0.5 * normal.dot(acceleration);
+    let linear = normal.dot(velocity);
+    let constant = normal.dot(start - vertex_a);
+
+    let min_time = min_time - ANALYTIC_TOLERANCE;
+    let max_time = max_time + ANALYTIC_TOLERANCE;
+
+    solve_quadratic(quadratic, linear, constant)
+        .into_iter()
+        .filter(|&t| t >= min_time && t <= max_time)
+        .map(|t| {
+            (
+                start + velocity * t + 0.5 * acceleration * t * t,
+                velocity + acceleration * t,
+            )
+        })
+        .find(|&(point, _)| is_inside(barycentric(triangle, point)))
+}
+
+/// Finds the real roots of `a * x² + b * x + c` in ascending order.
+fn solve_quadratic(a: f32, b: f32, c: f32) -> Vec<f32> {
+    if a.abs() < EPSILON {
+        if b.abs() < EPSILON {
+            Vec::new()
+        } else {
+            vec![-c / b]
+        }
+    } else {
+        let discriminant = b * b - 4.0 * a * c;
+        if discriminant < 0.0 {
+            Vec::new()
+        } else {
+            let root = discriminant.sqrt();
+            let x0 = (-b - root) / (2.0 * a);
+            let x1 = (-b + root) / (2.0 * a);
+            if x0 < x1 {
+                vec![x0, x1]
+            } else {
+                vec![x1, x0]
+            }
+        }
+    }
+}
+
+/// Checks that the geometry returned one intersection for each of the given queries of a
+/// batch, so a faulty custom geometry panics where the intersections are returned.
+fn batch_intersections<Q>(
+    intersections: Vec<Option<Intersection>>,
+    queries: &[Q],
+) -> Vec<Option<Intersection>> {
+    assert_eq!(
+        intersections.len(),
+        queries.len(),
+        "Geometry returned {} intersections for a batch of {} queries",
+        intersections.len(),
+        queries.len()
+    );
+    intersections
+}
+
+/// Key that sorts queries first by the octant of their direction and then by the position
+/// of their origin along a Morton curve through the given bounds.
+fn coherence_key(bounds: &Aabb, origin: Vec3, direction: Vec3) -> u64 {
+    let octant = (direction.x < 0.0) as u64
+        | ((direction.y < 0.0) as u64) << 1
+        | ((direction.z < 0.0) as u64) << 2;
+
+    let max_cell = ((1 << MORTON_BITS) - 1) as f32;
+    let mut morton = 0_u64;
+    for axis in 0..3 {
+        let extent = bounds.max[axis] - bounds.min[axis];
+        let relative = if extent > 0.0 {
+            ((origin[axis] - bounds.min[axis]) / extent)
+                .max(0.0)
+                .min(1.0)
+        } else {
+            0.0
+        };
+        let cell = (relative * max_cell) as u64;
+        for bit in 0..MORTON_BITS as u64 {
+            morton |= ((cell >> bit) & 1) << (3 * bit + axis as u64);
+        }
+    }
+
+    octant << (3 * MORTON_BITS) | morton
+}
+
+/// Moves the origin of a straight ray along its direction to avoid self-intersection.
+fn straight_origin(from: Vec3, direction: Vec3) -> Vec3 {
+    from + direction * STRAIGHT_OFFSET
+}
+
+fn is_front_face(normal: Vec3, incoming_direction: Vec3) -> bool {
+    incoming_direction.dot(normal) < 0.0
+}
+
+fn is_inside(barycentric: Vec3) -> bool {
+    barycentric.x >= -ANALYTIC_TOLERANCE
+        && barycentric.y >= -ANALYTIC_TOLERANCE
+        && barycentric.z >= -ANALYTIC_TOLERANCE
+}
+
 #[cfg(test)]
 mod test {
     extern crate aitios_asset;
 
     use super::*;
-    use geom::{Position, TangentSpace, TupleTriangle as Tri, Vec2, Vec3, Vertex};
+    use config::{Acceleration, FlowModel, Integration, Tracing, Wind};
+    use geom::{Position, TangentSpace, TupleTriangle as Tri, Vec3, Vertex};
+    use geometry::CompositeGeometry;
+    use primitive::Primitive;
     use scene::Mesh;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use test_fixtures::{quad, x_z_quad};
 
     #[test]
     fn test_straight_tracing() {
@@ -354,7 +1120,9 @@ mod test {
         // When shooting from the origin, should hit it
         let hit = tracer.trace_straight(origin, direction);
         assert!(hit.is_some(), "Expected to hit known vertex");
-        assert_ulps_eq!(hit.unwrap().intersection_point, known_vertex);
+        let hit = hit.unwrap();
+        assert_ulps_eq!(hit.intersection_point, known_vertex);
+        assert_relative_eq!(hit.distance, direction.magnitude(), epsilon = 0.0001);
 
         // In the other direction, it should be a miss
         let miss = tracer.trace_straight(origin, -direction);
@@ -386,6 +1154,180 @@ mod test {
         assert_ulps_eq!(hit.unwrap().intersection_point, known_vertex);
     }
 
+    #[test]
+    fn test_straight_up_parabola_analytic() {
+        let entities = aitios_asset::obj::load(
+            "test-scenes/buddha-scene-ton-source-mesh/buddha-scene-ton-source-sun.obj",
+        ).unwrap();
+
+        let known_vertex = entities[0].mesh.vertices().next().unwrap().position();
+
+        let tracer = Tracer::new_with_config(
+            entities.iter().flat_map(|ent| ent.mesh.triangles()),
+            &Tracing {
+                integration: Integration::Analytic {
+                    timestep: 1.0 / 60.0,
+                },
+                ..Default::default()
+            },
+        );
+
+        // Passes the vertex on the way up, halfway to the top of the parabola
+        let parabola_height = 10.0;
+        let origin = known_vertex + Vec3::new(0.0, -0.5 * parabola_height, 0.0);
+
+        let hit = tracer.trace_parabolic(origin, Vec3::new(0.0, 1.0, 0.0), parabola_height);
+        assert!(hit.is_some(), "Expected to hit known vertex");
+        assert_relative_eq!(
+            hit.unwrap().intersection_point,
+            known_vertex,
+            epsilon = 0.0001
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_gravity_direction_is_rejected() {
+        Tracer::new_with_config(
+            x_z_quad(),
+            &Tracing {
+                gravity_direction: Vec3::new(0.0, 0.0, 0.0),
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_zero_gravity_magnitude_is_rejected() {
+        Tracer::new_with_config(
+            x_z_quad(),
+            &Tracing {
+                gravity_magnitude: 0.0,
+                ..Default::default()
+            },
+        );
+    }
+
+    #[test]
+    fn test_analytic_parabola_on_flat() {
+        let tracer = Tracer::new_with_config(
+            x_z_quad(),
+            &Tracing {
+                integration: Integration::Analytic {
+                    timestep: 1.0 / 60.0,
+                },
+                ..Default::default()
+            },
+        );
+
+        // Thrown horizontally from a height of 1.0, with a parabola height of 0.04
+        // the horizontal distance is 2 * sqrt(0.04 * 1.0)
+        let hit = tracer.trace_parabolic(Vec3::new(0.0, 1.0, 0.0), Vec3::new(0.0, 0.0, 1.0), 0.04);
+        assert!(hit.is_some(), "Expected to land on quad");
+        assert_relative_eq!(
+            Vec3::new(0.0, 0.0, 0.4),
+            hit.unwrap().intersection_point,
+            epsilon = 0.001
+        );
+    }
+
+    #[test]
+    fn test_analytic_parabola_with_z_up() {
+        let tracer = Tracer::new_with_config(
+            x_y_quad(),
+            &Tracing {
+                gravity_direction: Vec3::new(0.0, 0.0, -1.0),
+                integration: Integration::Analytic {
+                    timestep: 1.0 / 60.0,
+                },
+                ..Default::default()
+            },
+        );
+
+        let hit = tracer.trace_parabolic(Vec3::new(0.0, 0.0, 1.0), Vec3::new(0.0, 1.0, 0.0), 0.04);
+        assert!(hit.is_some(), "Expected to land on quad");
+        assert_relative_eq!(
+            Vec3::new(0.0, 0.4, 0.0),
+            hit.unwrap().intersection_point,
+            epsilon = 0.001
+        );
+    }
+
+    #[test]
+    fn test_parabola_drifts_with_wind() {
+        let tracer = Tracer::new_with_config(
+            x_z_quad(),
+            &Tracing {
+                wind: Wind::Constant(Vec3::new(0.0, 0.0, 2.0)),
+                ..Default::default()
+            },
+        );
+
+        let origin = Vec3::new(0.0, 1.0, 0.0);
+        let down = Vec3::new(0.0, -1.0, 0.0);
+
+        let calm = tracer.trace_parabolic(origin, down, 0.0).unwrap();
+        assert_relative_eq!(calm.intersection_point.z, 0.0, epsilon = 0.0001);
+
+        let windy = tracer
+            .trace_parabolic_with_drag(origin, down, 0.0, 1.0)
+            .unwrap();
+        assert!(
+            windy.intersection_point.z > 0.1 && windy.intersection_point.z < 1.0,
+            "Expected ton with drag to drift with the wind, but landed at {:?}",
+            windy.intersection_point
+        );
+    }
+
+    #[test]
+    fn test_wind_profile() {
+        let tracer = Tracer::new_with_config(
+            x_z_quad(),
+            &Tracing {
+                wind: Wind::Profile {
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    reference_height: 10.0,
+                    exponent: 0.5,
+                    ground_height: -1.0,
+                },
+                ..Default::default()
+            },
+        );
+
+        assert_relative_eq!(
+            tracer.wind_at(Vec3::new(0.0, 9.0, 0.0)),
+            Vec3::new(1.0, 0.0, 0.0),
+            epsilon = 0.0001
+        );
+        assert_relative_eq!(
+            tracer.wind_at(Vec3::new(0.0, 1.5, 0.0)),
+            Vec3::new(0.5, 0.0, 0.0),
+            epsilon = 0.0001
+        );
+        assert_eq!(
+            tracer.wind_at(Vec3::new(0.0, -2.0, 0.0)),
+            Vec3::new(0.0, 0.0, 0.0)
+        );
+    }
+
+    #[test]
+    #[should_panic]
+    fn test_wind_profile_without_reference_height_is_rejected() {
+        Tracer::new_with_config(
+            x_z_quad(),
+            &Tracing {
+                wind: Wind::Profile {
+                    velocity: Vec3::new(1.0, 0.0, 0.0),
+                    reference_height: 0.0,
+                    exponent: 0.14,
+                    ground_height: 0.0,
+                },
+                ..Default::default()
+            },
+        );
+    }
+
     #[test]
     fn test_flow_on_flat() {
         // 1x1 quad on the X/Z plane
@@ -444,15 +1386,145 @@ mod test {
     }
 
     #[test]
-    #[ignore]
     fn test_flow_on_concave() {
-        unimplemented!();
+        // Floor with a wall standing on it at z = 0.5
+        let mut triangles = x_z_quad();
+        triangles.extend(quad(
+            Vec3::new(-1.0, 0.0, 0.5),
+            Vec3::new(1.0, 0.0, 0.5),
+            Vec3::new(1.0, 1.0, 0.5),
+            Vec3::new(-1.0, 1.0, 0.5),
+        ));
+
+        let origin = Vec3::new(0.0, 0.0, 0.0);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let flow_direction = Vec3::new(0.0, 0.0, 1.0);
+
+        for &flow_model in &[FlowModel::Diagonal, FlowModel::TangentialThenGravity] {
+            let tracer = Tracer::new_with_config(
+                triangles.clone(),
+                &Tracing {
+                    flow_model,
+                    ..Default::default()
+                },
+            );
+
+            let hit = tracer.trace_flow(origin, up, flow_direction, 0.8);
+            assert!(hit.is_some(), "Expected {:?} flow to hit wall", flow_model);
+            let hit = hit.unwrap().intersection_point;
+            assert_relative_eq!(hit.z, 0.5, epsilon = 0.0001);
+            assert!(
+                hit.y >= 0.0 && hit.y <= 1.0,
+                "Expected {:?} flow to end on wall, but ended at {:?}",
+                flow_model,
+                hit
+            );
+        }
     }
 
     #[test]
-    #[ignore]
     fn test_flow_on_convex() {
-        unimplemented!();
+        // Floor ending at z = 1.0, with a lower floor below it extending further
+        let mut triangles = x_z_quad();
+        triangles.extend(quad(
+            Vec3::new(-1.0, -1.0, 3.0),
+            Vec3::new(1.0, -1.0, 3.0),
+            Vec3::new(1.0, -1.0, -1.0),
+            Vec3::new(-1.0, -1.0, -1.0),
+        ));
+
+        let origin = Vec3::new(0.0, 0.0, 0.9);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let flow_direction = Vec3::new(0.0, 0.0, 1.0);
+
+        for &flow_model in &[FlowModel::Diagonal, FlowModel::TangentialThenGravity] {
+            let tracer = Tracer::new_with_config(
+                triangles.clone(),
+                &Tracing {
+                    flow_model,
+                    ..Default::default()
+                },
+            );
+
+            let hit = tracer.trace_flow(origin, up, flow_direction, 0.4);
+            assert!(hit.is_some(), "Expected {:?} flow to fall down", flow_model);
+            let hit = hit.unwrap().intersection_point;
+            assert_relative_eq!(hit.y, -1.0, epsilon = 0.0001);
+            assert!(
+                hit.z > 1.0,
+                "Expected {:?} flow to fall over the edge, but ended at {:?}",
+                flow_model,
+                hit
+            );
+        }
+    }
+
+    #[test]
+    fn test_default_diagonal_flow_follows_slope() {
+        // Floor ending at z = 1.0, continuing with a slope of 45 degrees downward
+        let mut triangles = x_z_quad();
+        triangles.extend(quad(
+            Vec3::new(-1.0, 0.0, 1.0),
+            Vec3::new(-1.0, -1.0, 2.0),
+            Vec3::new(1.0, -1.0, 2.0),
+            Vec3::new(1.0, 0.0, 1.0),
+        ));
+        let tracer = Tracer::new(triangles);
+
+        // Searches an absolute 1.1 units beyond the distance on a flat surface, so even
+        // short flow steps find the slope far below the diagonal
+        let origin = Vec3::new(0.0, 0.0, 0.99);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let flow_direction = Vec3::new(0.0, 0.0, 1.0);
+        match tracer.flow_step(origin, up, flow_direction, 0.05) {
+            FlowStep::Attached(hit) => {
+                let point = hit.intersection_point;
+                assert_relative_eq!(point.y, 1.0 - point.z, epsilon = 0.0001);
+                assert!(
+                    point.z > 1.1,
+                    "Expected to hit slope, but ended at {:?}",
+                    point
+                );
+            }
+            FlowStep::Detached { point, .. } => {
+                panic!("Expected to stay attached, but detached at {:?}", point)
+            }
+        }
+    }
+
+    #[test]
+    fn test_flow_detaches_above_close_floor() {
+        // Floor ending at z = 1.0, with a lower floor only slightly below it
+        let mut triangles = x_z_quad();
+        triangles.extend(quad(
+            Vec3::new(-1.0, -0.3, 3.0),
+            Vec3::new(1.0, -0.3, 3.0),
+            Vec3::new(1.0, -0.3, -1.0),
+            Vec3::new(-1.0, -0.3, -1.0),
+        ));
+        // Gravity pulls towards positive Z, so falling lands further than dropping
+        let tracer = Tracer::new_with_config(
+            triangles,
+            &Tracing {
+                flow_model: FlowModel::TangentialThenGravity,
+                gravity_direction: Vec3::new(0.0, -1.0, 1.0),
+                flow_adhesiveness: 0.01,
+                ..Default::default()
+            },
+        );
+
+        // Little adhesiveness, so the short drop misses the floor
+        let origin = Vec3::new(0.0, 0.0, 0.9);
+        let up = Vec3::new(0.0, 1.0, 0.0);
+        let flow_direction = Vec3::new(0.0, 0.0, 1.0);
+        let hit = tracer
+            .trace_flow(origin, up, flow_direction, 0.2)
+            .expect("Expected to fall onto the lower floor");
+        assert_relative_eq!(
+            hit.intersection_point,
+            Vec3::new(0.0, -0.3, 1.4),
+            epsilon = 0.0001
+        );
     }
 
     #[test]
@@ -488,34 +1560,511 @@ mod test {
         );
     }
 
-    fn x_z_quad() -> Vec<Tri<Vertex>> {
-        let left_front = Vertex {
-            position: Vec3::new(-1.0, 0.0, 1.0),
-            normal: Vec3::new(0.0, 0.0, 1.0),
-            texcoords: Vec2::new(0.0, 0.0),
+    #[test]
+    fn test_ballistic_from_outside_bounds() {
+        let tracer = Tracer::new(x_z_quad());
+
+        // Thrown horizontally from beside the quad, so it falls onto its center
+        let fall_time = (2.0 / 9.81_f32).sqrt();
+        let velocity = Vec3::new(3.0 / fall_time, 0.0, 0.0);
+        let hit = tracer
+            .trace_ballistic(Vec3::new(-3.0, 1.0, 0.0), velocity, 0.0)
+            .expect("Expected ton thrown from outside the scene bounds to land on the quad");
+
+        assert!(hit.intersection_point.x.abs() < 0.3);
+        assert!(hit.speed > velocity.magnitude());
+    }
+
+    #[test]
+    fn test_hit_reports_indexes() {
+        // Two quads on top of each other, belonging to different entities
+        let lower = x_z_quad();
+        let upper: Vec<Tri<Vertex>> = x_z_quad()
+            .into_iter()
+            .map(|Tri(a, b, c)| {
+                let lift = |v: Vertex| Vertex {
+                    position: v.position + Vec3::new(0.0, 1.0, 0.0),
+                    ..v
+                };
+                Tri(lift(a), lift(b), lift(c))
+            })
+            .collect();
+
+        let tracer = Tracer::new_with_entities(
+            lower
+                .into_iter()
+                .map(|t| (0, t))
+                .chain(upper.into_iter().map(|t| (1, t))),
+            &Default::default(),
+        );
+
+        let hit = tracer
+            .trace_straight(Vec3::new(0.1, 2.0, 0.2), Vec3::new(0.0, -1.0, 0.0))
+            .unwrap();
+        assert_eq!(hit.entity_idx, Some(1));
+        assert!(hit.triangle_idx >= 2);
+        assert_relative_eq!(hit.distance, 1.0, epsilon = 0.0001);
+
+        let (a, b, c) = tracer.triangle(hit.triangle_idx).unwrap().positions();
+        let weights = hit.barycentric.unwrap();
+        let reconstructed = a * weights.x + b * weights.y + c * weights.z;
+        assert_relative_eq!(reconstructed, hit.intersection_point, epsilon = 0.0001);
+
+        let hit = tracer
+            .trace_straight(Vec3::new(0.1, 0.5, 0.2), Vec3::new(0.0, -1.0, 0.0))
+            .unwrap();
+        assert_eq!(hit.entity_idx, Some(0));
+        assert!(hit.triangle_idx < 2);
+    }
+
+    #[test]
+    fn test_coincident_triangles_of_different_entities() {
+        // The same quad twice, with vertex normals telling the entities apart
+        let with_normal = |normal: Vec3| -> Vec<Tri<Vertex>> {
+            x_z_quad()
+                .into_iter()
+                .map(|Tri(a, b, c)| {
+                    let set_normal = |v: Vertex| Vertex { normal, ..v };
+                    Tri(set_normal(a), set_normal(b), set_normal(c))
+                })
+                .collect()
         };
+        let normals = [Vec3::new(1.0, 0.0, 0.0), Vec3::new(0.0, 1.0, 0.0)];
+        let triangles: Vec<(usize, Tri<Vertex>)> = normals
+            .iter()
+            .enumerate()
+            .flat_map(|(entity_idx, &normal)| {
+                with_normal(normal)
+                    .into_iter()
+                    .map(move |t| (entity_idx, t))
+            })
+            .collect();
+
+        let tracer = Tracer::new_with_entities(triangles.clone(), &Default::default());
+
+        for &(x, z) in &[(0.5, 0.2), (-0.5, -0.2), (0.1, -0.7)] {
+            let hit = tracer
+                .trace_straight(Vec3::new(x, 1.0, z), Vec3::new(0.0, -1.0, 0.0))
+                .expect("Expected to hit coincident quads");
+
+            // Index, entity and triangle all belong to the same of the two copies
+            let entity_idx = hit.entity_idx.unwrap();
+            let triangle_idx = hit.triangle_idx;
+            assert_eq!(entity_idx, triangles[triangle_idx].0);
+            let triangle = tracer.triangle(triangle_idx).unwrap();
+            assert_eq!(triangle.0.normal, normals[entity_idx]);
+        }
+    }
 
-        let right_front = Vertex {
-            position: Vec3::new(1.0, 0.0, 1.0),
-            normal: Vec3::new(0.0, 0.0, 1.0),
-            texcoords: Vec2::new(1.0, 0.0),
+    #[test]
+    fn test_bvh_agrees_with_octree() {
+        let entities = aitios_asset::obj::load(
+            "test-scenes/buddha-scene-ton-source-mesh/buddha-scene-ton-source-sun.obj",
+        ).unwrap();
+        let triangles = || entities.iter().flat_map(|ent| ent.mesh.triangles());
+
+        let octree_tracer = Tracer::new(triangles());
+        let bvh_config = Tracing {
+            acceleration: Acceleration::Bvh,
+            ..Default::default()
         };
+        let bvh_tracer = Tracer::new_with_config(triangles(), &bvh_config);
+
+        let octree_bounds = octree_tracer.bounds();
+        let bvh_bounds = bvh_tracer.bounds();
+        assert_relative_eq!(bvh_bounds.min, octree_bounds.min, epsilon = 0.0001);
+        assert_relative_eq!(bvh_bounds.max, octree_bounds.max, epsilon = 0.0001);
 
-        let left_rear = Vertex {
-            position: Vec3::new(-1.0, 0.0, -1.0),
-            normal: Vec3::new(0.0, 0.0, 1.0),
-            texcoords: Vec2::new(0.0, 1.0),
+        // Shoot from the inside of the dome towards all of its vertices and beyond
+        let origin = Vec3::new(0.0, 0.1, 0.2);
+        for vertex in entities[0].mesh.vertices().take(50) {
+            let direction = vertex.position() - origin + Vec3::new(0.01, 0.02, -0.01);
+
+            let octree_hit = octree_tracer.trace_straight(origin, direction);
+            let bvh_hit = bvh_tracer.trace_straight(origin, direction);
+            assert_eq!(octree_hit.is_some(), bvh_hit.is_some());
+
+            if let (Some(octree_hit), Some(bvh_hit)) = (octree_hit, bvh_hit) {
+                assert_eq!(octree_hit.triangle_idx, bvh_hit.triangle_idx);
+                assert_relative_eq!(
+                    octree_hit.intersection_point,
+                    bvh_hit.intersection_point,
+                    epsilon = 0.0001
+                );
+                assert_relative_eq!(octree_hit.distance, bvh_hit.distance, epsilon = 0.0001);
+            }
+        }
+
+        // Axis-aligned segments, like flow steps on flat floors, sharing a coordinate with
+        // a vertex on an axis they are parallel to, so they start on node boundaries
+        let extent = (octree_bounds.max - octree_bounds.min).magnitude();
+        for vertex in entities[0].mesh.vertices().take(50) {
+            for axis in 0..3 {
+                for &sign in &[-1.0, 1.0] {
+                    let mut direction = Vec3::new(0.0, 0.0, 0.0);
+                    direction[axis] = sign;
+                    // Shift off the vertex along one parallel axis, keep the other one
+                    let mut offset = Vec3::new(0.0, 0.0, 0.0);
+                    offset[(axis + 1) % 3] = 0.013;
+                    let origin = vertex.position() + offset - direction * extent;
+
+                    let octree_hit = octree_tracer.geometry.line_segment_intersection(
+                        origin,
+                        direction,
+                        2.0 * extent,
+                    );
+                    let bvh_hit = bvh_tracer.geometry.line_segment_intersection(
+                        origin,
+                        direction,
+                        2.0 * extent,
+                    );
+                    assert_eq!(
+                        octree_hit.is_some(),
+                        bvh_hit.is_some(),
+                        "Expected same outcome for segment from {:?} in direction {:?}",
+                        origin,
+                        direction
+                    );
+
+                    if let (Some(octree_hit), Some(bvh_hit)) = (octree_hit, bvh_hit) {
+                        assert_relative_eq!(octree_hit.t, bvh_hit.t, epsilon = 0.0001);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_hit_reports_side() {
+        // Normal of the quad points up in Y direction
+        let tracer = Tracer::new(x_z_quad());
+
+        let from_above = tracer
+            .trace_straight(Vec3::new(0.1, 1.0, 0.2), Vec3::new(0.0, -1.0, 0.0))
+            .expect("Expected to hit quad from above");
+        assert!(from_above.front_face);
+
+        let from_below = tracer
+            .trace_straight(Vec3::new(0.1, -1.0, 0.2), Vec3::new(0.0, 1.0, 0.0))
+            .expect("Expected to hit quad from below");
+        assert!(!from_below.front_face);
+    }
+
+    #[test]
+    fn test_batches_agree_with_single_queries() {
+        let entities = aitios_asset::obj::load(
+            "test-scenes/buddha-scene-ton-source-mesh/buddha-scene-ton-source-sun.obj",
+        ).unwrap();
+
+        // Rays from inside the dome towards its vertices, mixed with rays that miss
+        let origin = Vec3::new(0.0, 0.1, 0.2);
+        let rays: Vec<(Vec3, Vec3)> = entities[0]
+            .mesh
+            .vertices()
+            .take(200)
+            .enumerate()
+            .map(|(idx, vertex)| {
+                let direction = vertex.position() - origin;
+                if idx % 3 == 0 {
+                    (origin, -direction)
+                } else {
+                    (origin, direction)
+                }
+            })
+            .collect();
+
+        // Both acceleration structures, the hierarchy traces groups as packets
+        for &acceleration in &[Acceleration::Octree, Acceleration::Bvh] {
+            let tracer = Tracer::new_with_config(
+                entities.iter().flat_map(|ent| ent.mesh.triangles()),
+                &Tracing {
+                    acceleration,
+                    ..Default::default()
+                },
+            );
+
+            let batch_hits = tracer.trace_straight_batch(&rays);
+            assert_eq!(batch_hits.len(), rays.len());
+
+            for (&(from, direction), batch_hit) in rays.iter().zip(batch_hits) {
+                let single_hit = tracer.trace_straight(from, direction);
+                assert_same_hits(single_hit, batch_hit);
+            }
+
+            // Flow on from where the rays hit, stepping once like dripping tons do and tracing
+            // the whole flow, with both flow models
+            for &flow_model in &[FlowModel::Diagonal, FlowModel::TangentialThenGravity] {
+                let tracer = Tracer::new_with_config(
+                    entities.iter().flat_map(|ent| ent.mesh.triangles()),
+                    &Tracing {
+                        acceleration,
+                        flow_model,
+                        ..Default::default()
+                    },
+                );
+                let gravity = tracer.gravity_direction();
+
+                let queries: Vec<FlowQuery> = rays
+                    .iter()
+                    .filter_map(|&(from, direction)| tracer.trace_straight(from, direction))
+                    .map(|hit| {
+                        // Flow downhill on the side that was hit
+                        let up = if hit.front_face {
+                            hit.normal
+                        } else {
+                            -hit.normal
+                        };
+                        let downhill = gravity - up * gravity.dot(up);
+                        let tangential_direction = if downhill.magnitude2() > 0.0001 {
+                            downhill.normalize()
+                        } else {
+                            tracer.triangle(hit.triangle_idx).unwrap().tangent()
+                        };
+
+                        FlowQuery {
+                            from: hit.intersection_point,
+                            up,
+                            tangential_direction,
+                            flow_distance: 0.05,
+                        }
+                    })
+                    .collect();
+                assert!(!queries.is_empty());
+
+                let batch_steps = tracer.flow_step_batch(&queries);
+                let batch_hits = tracer.trace_flow_batch(&queries);
+                assert_eq!(batch_steps.len(), queries.len());
+                assert_eq!(batch_hits.len(), queries.len());
+
+                for ((q, batch_step), batch_hit) in queries.iter().zip(batch_steps).zip(batch_hits)
+                {
+                    let single_step =
+                        tracer.flow_step(q.from, q.up, q.tangential_direction, q.flow_distance);
+                    match (single_step, batch_step) {
+                        (FlowStep::Attached(single_hit), FlowStep::Attached(batch_hit)) => {
+                            assert_same_hits(Some(single_hit), Some(batch_hit))
+                        }
+                        (
+                            FlowStep::Detached { point, distance },
+                            FlowStep::Detached {
+                                point: batch_point,
+                                distance: batch_distance,
+                            },
+                        ) => {
+                            assert_relative_eq!(point, batch_point, epsilon = 0.0001);
+                            assert_relative_eq!(distance, batch_distance, epsilon = 0.0001);
+                        }
+                        (single_step, batch_step) => panic!(
+                            "Expected equal flow steps, got {:?} and {:?}",
+                            single_step, batch_step
+                        ),
+                    }
+
+                    let single_hit =
+                        tracer.trace_flow(q.from, q.up, q.tangential_direction, q.flow_distance);
+                    assert_same_hits(single_hit, batch_hit);
+                }
+            }
+        }
+    }
+
+    fn assert_same_hits(single_hit: Option<Hit>, batch_hit: Option<Hit>) {
+        assert_eq!(single_hit.is_some(), batch_hit.is_some());
+
+        if let (Some(single_hit), Some(batch_hit)) = (single_hit, batch_hit) {
+            assert_eq!(single_hit.triangle_idx, batch_hit.triangle_idx);
+            assert_relative_eq!(
+                single_hit.intersection_point,
+                batch_hit.intersection_point,
+                epsilon = 0.0001
+            );
+            assert_relative_eq!(single_hit.distance, batch_hit.distance, epsilon = 0.0001);
+        }
+    }
+
+    #[test]
+    fn test_custom_geometry() {
+        // Counts queries and forwards them to a quad, without exposing its triangles
+        struct CountingGeometry {
+            quad: TriangleGeometry,
+            queries: AtomicUsize,
+        }
+
+        impl Geometry for CountingGeometry {
+            fn ray_intersection(&self, origin: Vec3, direction: Vec3) -> Option<Intersection> {
+                self.queries.fetch_add(1, Ordering::SeqCst);
+                self.quad.ray_intersection(origin, direction)
+            }
+
+            fn line_segment_intersection(
+                &self,
+                origin: Vec3,
+                direction: Vec3,
+                length: f32,
+            ) -> Option<Intersection> {
+                self.queries.fetch_add(1, Ordering::SeqCst);
+                self.quad
+                    .line_segment_intersection(origin, direction, length)
+            }
+
+            fn bounds(&self) -> Aabb {
+                self.quad.bounds()
+            }
+        }
+
+        let geometry = CountingGeometry {
+            quad: TriangleGeometry::new(x_z_quad(), Acceleration::Octree),
+            queries: AtomicUsize::new(0),
+        };
+        let tracer = Tracer::with_geometry(geometry, &Default::default());
+
+        let hit = tracer
+            .trace_straight(Vec3::new(0.1, 1.0, 0.2), Vec3::new(0.0, -1.0, 0.0))
+            .expect("Expected to hit quad through custom geometry");
+        assert_relative_eq!(hit.distance, 1.0, epsilon = 0.0001);
+        assert_relative_eq!(hit.normal, Vec3::new(0.0, 1.0, 0.0));
+        assert!(hit.front_face);
+        assert_eq!(hit.entity_idx, None);
+        assert!(tracer.triangle(hit.triangle_idx).is_none());
+        assert_eq!(tracer.geometry.queries.load(Ordering::SeqCst), 1);
+
+        // Without triangles, parabolas are intersected segment by segment
+        let hit = tracer
+            .trace_parabolic(Vec3::new(0.1, 0.0, 0.2), Vec3::new(0.0, 1.0, 0.0), 0.5)
+            .expect("Expected parabola to land on quad through custom geometry");
+        assert_relative_eq!(hit.intersection_point.y, 0.0, epsilon = 0.0001);
+        assert!(hit.front_face);
+    }
+
+    #[test]
+    #[should_panic(expected = "Geometry returned 0 intersections for a batch of 1 queries")]
+    fn test_batch_with_missing_intersections_panics() {
+        // Forgets the intersections of batched rays
+        struct ForgetfulGeometry(TriangleGeometry);
+
+        impl Geometry for ForgetfulGeometry {
+            fn ray_intersection(&self, origin: Vec3, direction: Vec3) -> Option<Intersection> {
+                self.0.ray_intersection(origin, direction)
+            }
+
+            fn line_segment_intersection(
+                &self,
+                origin: Vec3,
+                direction: Vec3,
+                length: f32,
+            ) -> Option<Intersection> {
+                self.0.line_segment_intersection(origin, direction, length)
+            }
+
+            fn ray_intersections(&self, _rays: &[(Vec3, Vec3)]) -> Vec<Option<Intersection>> {
+                Vec::new()
+            }
+
+            fn bounds(&self) -> Aabb {
+                self.0.bounds()
+            }
+        }
+
+        let geometry = ForgetfulGeometry(TriangleGeometry::new(x_z_quad(), Acceleration::Octree));
+        let tracer = Tracer::with_geometry(geometry, &Default::default());
+        tracer.trace_straight_batch(&[(Vec3::new(0.1, 1.0, 0.2), Vec3::new(0.0, -1.0, 0.0))]);
+    }
+
+    #[test]
+    fn test_primitives() {
+        let config = Tracing {
+            integration: Integration::Analytic {
+                timestep: 1.0 / 60.0,
+            },
+            ..Default::default()
         };
+        let scene = || {
+            let primitives = vec![
+                Primitive::Plane {
+                    point: Vec3::new(0.0, -1.0, 0.0),
+                    normal: Vec3::new(0.0, 1.0, 0.0),
+                },
+                Primitive::Sphere {
+                    center: Vec3::new(0.0, 3.0, 0.0),
+                    radius: 0.5,
+                },
+            ];
+            let triangles = TriangleGeometry::new(x_z_quad(), Acceleration::Octree);
+            CompositeGeometry::new(triangles, primitives)
+        };
+        let tracer = Tracer::with_geometry(scene(), &config);
+        let down = Vec3::new(0.0, -1.0, 0.0);
+
+        // Triangles in front of primitives are still hit
+        let hit = tracer
+            .trace_straight(Vec3::new(0.0, 1.0, 0.0), down)
+            .unwrap();
+        assert!(hit.triangle_idx < 2);
+        assert!(hit.has_surfels);
+        assert!(tracer.triangle(hit.triangle_idx).is_some());
+
+        // Beside the quad, the ground plane is hit, indexed after the two triangles
+        let hit = tracer
+            .trace_straight(Vec3::new(5.0, 1.0, 0.0), down)
+            .unwrap();
+        assert_eq!(hit.triangle_idx, 2);
+        assert!(!hit.has_surfels);
+        assert!(tracer.triangle(hit.triangle_idx).is_none());
+        assert_eq!(hit.entity_idx, None);
+        assert_eq!(hit.barycentric, None);
+        assert!(hit.front_face);
+        assert_relative_eq!(hit.distance, 2.0, epsilon = 0.0001);
+        assert_relative_eq!(hit.normal, Vec3::new(0.0, 1.0, 0.0));
+
+        // The sphere is hit from outside and its normal points outward
+        let hit = tracer
+            .trace_straight(Vec3::new(0.0, 5.0, 0.0), down)
+            .unwrap();
+        assert_eq!(hit.triangle_idx, 3);
+        assert!(hit.front_face);
+        assert_relative_eq!(
+            hit.intersection_point,
+            Vec3::new(0.0, 3.5, 0.0),
+            epsilon = 0.0001
+        );
+        assert_relative_eq!(hit.normal, Vec3::new(0.0, 1.0, 0.0), epsilon = 0.0001);
+
+        // Thrown horizontally from a height of 2.0 above the plane, far outside the quad,
+        // with a parabola height of 0.04 the horizontal distance is 2 * sqrt(0.04 * 2.0)
+        let hit = tracer
+            .trace_parabolic(Vec3::new(0.0, 1.0, 3.0), Vec3::new(0.0, 0.0, 1.0), 0.04)
+            .expect("Expected to land on the ground plane");
+        assert_eq!(hit.triangle_idx, 2);
+        assert_relative_eq!(
+            Vec3::new(0.0, -1.0, 3.0 + 2.0 * (0.04f32 * 2.0).sqrt()),
+            hit.intersection_point,
+            epsilon = 0.001
+        );
+
+        // Unbounded planes do not end the flight, the maximum flight time does
+        let tracer = Tracer::with_geometry(
+            scene(),
+            &Tracing {
+                max_flight_time: 0.1,
+                ..config
+            },
+        );
+        let hit = tracer.trace_parabolic(Vec3::new(5.0, 1.0, 0.0), Vec3::new(0.0, 1.0, 0.0), 0.04);
+        assert!(hit.is_none(), "Expected to stop in flight");
+    }
 
-        let right_rear = Vertex {
-            position: Vec3::new(1.0, 0.0, -1.0),
-            normal: Vec3::new(0.0, 0.0, 1.0),
-            texcoords: Vec2::new(1.0, 1.0),
+    /// Same as `x_z_quad`, but rotated so it lies on the X/Y plane.
+    fn x_y_quad() -> Vec<Tri<Vertex>> {
+        let rotate = |v: Vec3| Vec3::new(v.x, -v.z, v.y);
+        let z_up = |vertex: Vertex| Vertex {
+            position: rotate(vertex.position),
+            normal: rotate(vertex.normal),
+            ..vertex
         };
 
-        vec![
-            Tri(left_front.clone(), right_front, right_rear.clone()),
-            Tri(left_front, right_rear, left_rear),
-        ]
+        x_z_quad()
+            .into_iter()
+            .map(|Tri(a, b, c)| Tri(z_up(a), z_up(b), z_up(c)))
+            .collect()
     }
 }
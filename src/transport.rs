@@ -1,4 +1,4 @@
-use motion::MotionType;
+use motion::{MotionType, TransferMultipliers};
 use std::marker::PhantomData;
 use surf::Surfel;
 use ton::Ton;
@@ -30,26 +30,34 @@ where
     ) {
         let (next_motion_type, surfel_idxs) = interaction_info;
         let count_weight = (surfel_idxs.len() as f32).recip();
+        let multipliers = ton.motion_multipliers.get(*next_motion_type);
 
         match next_motion_type {
             // settle substance exchange
             MotionType::Settled => {
                 for idx in surfel_idxs {
-                    S::transport(ton, surface[*idx].data_mut(), count_weight);
+                    S::transport(ton, surface[*idx].data_mut(), count_weight, multipliers);
                 }
             }
             // non-settle substance exchange, a.k.a bounce
             _ => {
                 for idx in surfel_idxs {
-                    B::transport(ton, surface[*idx].data_mut(), count_weight);
+                    B::transport(ton, surface[*idx].data_mut(), count_weight, multipliers);
                 }
             }
         }
     }
 }
 
+/// Exchange of substances between a ton and a single surfel it interacts with,
+/// applying the transfer multipliers of the motion type the ton continues with.
 pub trait Rule {
-    fn transport(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32);
+    fn transport(
+        ton: &mut Ton,
+        interacting_surfel: &mut SurfelData,
+        count_weight: f32,
+        multipliers: TransferMultipliers,
+    );
 }
 
 pub struct Absorb;
@@ -59,10 +67,18 @@ pub struct Deposit;
 pub struct Differential;
 
 impl Rule for Differential {
-    fn transport(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32) {
+    fn transport(
+        ton: &mut Ton,
+        interacting_surfel: &mut SurfelData,
+        count_weight: f32,
+        multipliers: TransferMultipliers,
+    ) {
+        let weight = ton.weight;
         let to_surf_rates = ton.pickup_rates.iter()
             .zip(interacting_surfel.deposition_rates.iter())
-            .map(|(t, s)| count_weight * (s - t));
+            .map(|(t, s)| count_weight * (multipliers.deposition * s - multipliers.pickup * t))
+            // Multipliers may scale rates beyond transferring everything
+            .map(|rate| rate.max(-1.0).min(1.0));
 
         let substances = ton.substances.iter_mut().zip(interacting_surfel.substances.iter_mut());
 
@@ -70,10 +86,11 @@ impl Rule for Differential {
             if rate > 0.0 {
                 let transfer = *ton * rate;
                 *ton -= transfer;
-                *surf += transfer;
+                *surf += weight * transfer;
             } else {
-                let transfer = *surf * -rate;
-                *surf -= transfer;
+                // The surfel gives the weighted transfer, so it cannot give more than it has
+                let transfer = (*surf * -rate).min(*surf / weight);
+                *surf = (*surf - weight * transfer).max(0.0);
                 *ton += transfer;
             }
         }
@@ -81,28 +98,50 @@ impl Rule for Differential {
 }
 
 impl Rule for Absorb {
-    fn transport(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32) {
-        absorb(ton, interacting_surfel, count_weight);
+    fn transport(
+        ton: &mut Ton,
+        interacting_surfel: &mut SurfelData,
+        count_weight: f32,
+        multipliers: TransferMultipliers,
+    ) {
+        absorb(ton, interacting_surfel, count_weight, multipliers);
     }
 }
 
 impl Rule for Deposit {
-    fn transport(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32) {
-        deposit(ton, interacting_surfel, count_weight);
+    fn transport(
+        ton: &mut Ton,
+        interacting_surfel: &mut SurfelData,
+        count_weight: f32,
+        multipliers: TransferMultipliers,
+    ) {
+        deposit(ton, interacting_surfel, count_weight, multipliers);
     }
 }
 
 pub struct AbsorbThenDeposit;
 impl Rule for AbsorbThenDeposit {
-    fn transport(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32) {
-        absorb(ton, interacting_surfel, count_weight);
-        deposit(ton, interacting_surfel, count_weight);
+    fn transport(
+        ton: &mut Ton,
+        interacting_surfel: &mut SurfelData,
+        count_weight: f32,
+        multipliers: TransferMultipliers,
+    ) {
+        absorb(ton, interacting_surfel, count_weight, multipliers);
+        deposit(ton, interacting_surfel, count_weight, multipliers);
     }
 }
 
 pub struct DepositAll;
 impl Rule for DepositAll {
-    fn transport(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32) {
+    /// Disposes of everything regardless of multipliers, since scaling would
+    /// either leave substance in the ton or create new substance.
+    fn transport(
+        ton: &mut Ton,
+        interacting_surfel: &mut SurfelData,
+        count_weight: f32,
+        _multipliers: TransferMultipliers,
+    ) {
         deposit_all(ton, interacting_surfel, count_weight);
     }
 }
@@ -114,20 +153,29 @@ fn deposit_all(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight:
         "Surfel and ton have unequal amount of materials, cannot transport"
     );
 
+    let weight = ton.weight;
     interacting_surfel.substances.iter_mut()
         .zip(ton.substances.iter())
-        .for_each(|(s, t)| *s += count_weight * t)
+        .for_each(|(s, t)| *s += weight * count_weight * t)
 }
 
-/// Deposits the materials in the ton in the interacting surfel, not mutating
-/// the ton
-fn deposit(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32) {
+/// Deposits the materials in the ton in the interacting surfel.
+/// The deposition rates of the surfel are scaled with the share of the surfel
+/// in the contact and the deposition multiplier of the motion type. The surfel
+/// receives the deposited amount times the weight of the ton.
+fn deposit(
+    ton: &mut Ton,
+    interacting_surfel: &mut SurfelData,
+    count_weight: f32,
+    multipliers: TransferMultipliers,
+) {
     assert_eq!(
         interacting_surfel.substances.len(),
         ton.substances.len(),
         "Surfel and ton have unequal amount of materials, cannot transport"
     );
 
+    let weight = ton.weight;
     let material_transports = interacting_surfel.deposition_rates.iter().zip(
         ton.substances
             .iter_mut()
@@ -135,24 +183,35 @@ fn deposit(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32
     );
 
     for (ref deposition_rate, (ref mut ton_material, ref mut surfel_material)) in material_transports {
-        // pickup rate gets equally distributed between all interacting surfels
-        let deposition_rate = count_weight * *deposition_rate;
+        // deposition rate gets equally distributed between all interacting surfels
+        let deposition_rate = count_weight * multipliers.deposition * *deposition_rate;
+        // Multipliers may scale the rate beyond depositing everything
+        let deposition_rate = deposition_rate.max(0.0).min(1.0);
         let transport_amount = deposition_rate * **ton_material;
         **ton_material = (**ton_material - transport_amount).max(0.0);
-        **surfel_material = (**surfel_material + transport_amount).max(0.0);
+        **surfel_material = (**surfel_material + weight * transport_amount).max(0.0);
     }
 }
 
 /// Makes the ton pick up material from a surfel it is interacting with.
 /// The pick up rate can also be negative, the ton then deposits material on contact
 /// instead of accumulating.
-fn absorb(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32) {
+/// The pickup rates of the ton are scaled with the share of the surfel in the
+/// contact and the pickup multiplier of the motion type. The surfel gives or
+/// receives the picked up amount times the weight of the ton.
+fn absorb(
+    ton: &mut Ton,
+    interacting_surfel: &mut SurfelData,
+    count_weight: f32,
+    multipliers: TransferMultipliers,
+) {
     assert_eq!(
         interacting_surfel.substances.len(),
         ton.substances.len(),
         "Surfel and ton have unequal amount of materials, cannot transport"
     );
 
+    let weight = ton.weight;
     let material_transports = ton.pickup_rates.iter().zip(
         ton.substances
             .iter_mut()
@@ -161,15 +220,155 @@ fn absorb(ton: &mut Ton, interacting_surfel: &mut SurfelData, count_weight: f32)
 
     for (ref pickup_rate, (ref mut ton_material, ref mut surfel_material)) in material_transports {
         // pickup rate gets equally distributed between all interacting surfels
-        let pickup_rate = count_weight * *pickup_rate;
+        let pickup_rate = count_weight * multipliers.pickup * *pickup_rate;
+        // Multipliers may scale the rate beyond picking up or depositing everything
+        let pickup_rate = pickup_rate.max(-1.0).min(1.0);
 
         let transport_amount = pickup_rate * if pickup_rate >= 0.0 {
             **surfel_material
         } else {
             **ton_material
         };
+        // The surfel gives the weighted amount, so it cannot give more than it has
+        let transport_amount = transport_amount.min(**surfel_material / weight);
 
-        **surfel_material = (**surfel_material - transport_amount).max(0.0);
+        **surfel_material = (**surfel_material - weight * transport_amount).max(0.0);
         **ton_material = (**ton_material + transport_amount).max(0.0);
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use geom::{TupleTriangle, Vec2, Vec3, Vertex};
+    use surf::{SurfaceBuilder, SurfelSampling};
+    use ton::TonSourceBuilder;
+
+    const MOTION_TYPES: [MotionType; 4] = [
+        MotionType::Straight,
+        MotionType::Parabolic,
+        MotionType::Flow,
+        MotionType::Settled,
+    ];
+
+    #[test]
+    fn test_straight_multipliers() {
+        assert_multipliers_only_apply_to(MotionType::Straight);
+    }
+
+    #[test]
+    fn test_parabolic_multipliers() {
+        assert_multipliers_only_apply_to(MotionType::Parabolic);
+    }
+
+    #[test]
+    fn test_flow_multipliers() {
+        assert_multipliers_only_apply_to(MotionType::Flow);
+    }
+
+    #[test]
+    fn test_settled_multipliers() {
+        assert_multipliers_only_apply_to(MotionType::Settled);
+    }
+
+    /// Halves pickup and doubles deposition for the given motion type and checks that
+    /// contacts continuing with it transfer accordingly, while all others are unaffected.
+    fn assert_multipliers_only_apply_to(multiplied: MotionType) {
+        for &next_motion_type in &MOTION_TYPES {
+            let (ton, surf) = absorb_then_deposit(multiplied, next_motion_type);
+
+            if next_motion_type == multiplied {
+                // Picks up 0.2 * 0.5 of the surfel, then deposits 0.1 * 2.0 of the ton
+                assert_relative_eq!(ton, 1.1 * 0.8, epsilon = 0.0001);
+                assert_relative_eq!(surf, 0.9 + 1.1 * 0.2, epsilon = 0.0001);
+            } else {
+                // Picks up 0.2 of the surfel, then deposits 0.1 of the ton
+                assert_relative_eq!(ton, 1.2 * 0.9, epsilon = 0.0001);
+                assert_relative_eq!(surf, 0.8 + 1.2 * 0.1, epsilon = 0.0001);
+            }
+        }
+    }
+
+    #[test]
+    fn test_transfers_conserve_substance() {
+        assert_conserves::<Absorb>();
+        assert_conserves::<Deposit>();
+        assert_conserves::<AbsorbThenDeposit>();
+        assert_conserves::<Differential>();
+    }
+
+    /// Checks that contacts with the given rule conserve the total substance in ton and
+    /// surfel for multipliers that scale the rates above one, for positive and negative
+    /// pickup rates and tons weighted above one.
+    fn assert_conserves<R: Rule>() {
+        for &(pickup_rate, weight) in &[(0.3, 1.0), (-0.3, 1.0), (0.3, 3.0), (-0.3, 3.0)] {
+            let mut ton = TonSourceBuilder::new()
+                .substances(&vec![1.0])
+                .pickup_rates(vec![pickup_rate])
+                .transfer_multipliers(MotionType::Flow, 5.0, 5.0)
+                .build()
+                .emit_one()
+                .ton;
+            ton.weight = weight;
+            let mut surfels = single_surfel(0.3).samples;
+            let total = |ton: &Ton, surfels: &Vec<Surfel<Vertex, SurfelData>>| {
+                ton.weight * ton.substances[0] + surfels[0].data().substances[0]
+            };
+
+            let before = total(&ton, &surfels);
+            transport::<R, R>().perform(&mut ton, &mut surfels, &(MotionType::Flow, vec![0]));
+            let after = total(&ton, &surfels);
+
+            assert_relative_eq!(before, after, epsilon = 0.0001);
+            assert!(ton.substances[0] >= 0.0 && surfels[0].data().substances[0] >= 0.0);
+        }
+    }
+
+    /// Performs a contact with a single surfel after which the ton continues with the
+    /// given motion type and returns the substance in the ton and surfel afterwards.
+    fn absorb_then_deposit(multiplied: MotionType, next_motion_type: MotionType) -> (f32, f32) {
+        let mut ton = TonSourceBuilder::new()
+            .substances(&vec![1.0])
+            .pickup_rates(vec![0.2])
+            .transfer_multipliers(multiplied, 0.5, 2.0)
+            .build()
+            .emit_one()
+            .ton;
+
+        let mut surfels = single_surfel(0.1).samples;
+        transport::<AbsorbThenDeposit, AbsorbThenDeposit>().perform(
+            &mut ton,
+            &mut surfels,
+            &(next_motion_type, vec![0]),
+        );
+
+        (ton.substances[0], surfels[0].data().substances[0])
+    }
+
+    fn single_surfel(deposition_rate: f32) -> ::surf::Surface<Surfel<Vertex, SurfelData>> {
+        let vertex = |x, y| Vertex {
+            position: Vec3::new(x, y, 0.0),
+            normal: Vec3::new(0.0, 0.0, 1.0),
+            texcoords: Vec2::new(x, y),
+        };
+        let prototype = SurfelData {
+            entity_idx: 0,
+            delta_straight: 0.0,
+            delta_parabolic: 0.0,
+            delta_flow: 0.0,
+            substances: vec![1.0],
+            deposition_rates: vec![deposition_rate],
+            rules: vec![],
+        };
+
+        let triangle = TupleTriangle(vertex(0.0, 0.0), vertex(0.1, 0.0), vertex(0.0, 0.1));
+
+        let mut surface = SurfaceBuilder::new()
+            .sampling(SurfelSampling::MinimumDistance(1.0))
+            .sample_triangles(vec![triangle], &prototype)
+            .build();
+        surface.samples.truncate(1);
+        assert_eq!(surface.samples.len(), 1);
+        surface
+    }
+}